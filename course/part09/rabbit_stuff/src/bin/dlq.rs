@@ -0,0 +1,60 @@
+use clap::{Parser, Subcommand};
+use tracing::{info, level_filters::LevelFilter};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
+
+use rabbit_stuff::rabbit::Rabbit;
+
+/// inspects & replays the messages consumers have given up on
+#[derive(Debug, Parser)]
+struct Args {
+    #[arg(long, default_value = "amqp://localhost:5672")]
+    amqp_url: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// prints dead letters as json lines, leaving them on the queue
+    Inspect {
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// republishes dead letters to the queues they came from, with their retries reset
+    Replay {
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // logs go to stderr so stdout is just the dead letters
+    Registry::default()
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .try_init()?;
+
+    let args = Args::parse();
+    let rabbit = Rabbit::new(&args.amqp_url).await?;
+
+    match args.command {
+        Command::Inspect { limit } => {
+            for dead_letter in rabbit.dead_letters(limit).await? {
+                println!("{}", serde_json::to_string(&dead_letter)?);
+            }
+        }
+        Command::Replay { limit } => {
+            let replayed = rabbit.replay_dead_letters(limit).await?;
+            info!("replayed {replayed} dead letters");
+        }
+    }
+
+    rabbit.close().await?;
+
+    Ok(())
+}
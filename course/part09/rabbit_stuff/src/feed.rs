@@ -0,0 +1,44 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::error;
+
+// a message a consumer has successfully processed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProcessedMessage {
+    pub message_type: String,
+    pub message: serde_json::Value,
+}
+
+// broadcasts processed messages to anyone who's subscribed, clones share the same
+// channel. subscribers that fall more than `capacity` messages behind miss the oldest
+// ones rather than slowing the consumers down
+#[derive(Debug, Clone)]
+pub struct ProcessedFeed {
+    sender: broadcast::Sender<ProcessedMessage>,
+}
+
+impl ProcessedFeed {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn publish<S: Serialize>(&self, message_type: &str, message: &S) {
+        let message = match serde_json::to_value(message) {
+            Ok(message) => message,
+            Err(err) => {
+                error!("failed to serialize processed {message_type} message: {err}");
+                return;
+            }
+        };
+        // only errors if there's no one subscribed, which is fine
+        let _ = self.sender.send(ProcessedMessage {
+            message_type: message_type.to_string(),
+            message,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ProcessedMessage> {
+        self.sender.subscribe()
+    }
+}
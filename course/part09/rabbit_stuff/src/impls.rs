@@ -12,13 +12,25 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use tracing::info;
 
-use crate::rabbit::{RabbitConsumer, Requeue, ShouldRequeue, MESSAGE_TYPE, MESSAGE_TYPE_2};
+use crate::{
+    feed::ProcessedFeed,
+    jobs::{JobMessage, JobStatus, JobStore, JobStoreError},
+    rabbit::{
+        RabbitConsumer,
+        Requeue,
+        ShouldRequeue,
+        JOB_MESSAGE_TYPE,
+        MESSAGE_TYPE,
+        MESSAGE_TYPE_2,
+    },
+};
 
 // a consumer with a counter for its own requests & a shared counter with the other consumer
 #[derive(Debug, Default)]
 pub struct MyMessageConsumer {
     received: AtomicUsize,
     received_all: Arc<AtomicUsize>,
+    feed: Option<ProcessedFeed>,
 }
 
 impl MyMessageConsumer {
@@ -26,8 +38,15 @@ impl MyMessageConsumer {
         MyMessageConsumer {
             received: Default::default(),
             received_all,
+            feed: None,
         }
     }
+
+    // publishes every message that's processed to the feed
+    pub fn with_feed(mut self, feed: ProcessedFeed) -> Self {
+        self.feed = Some(feed);
+        self
+    }
 }
 
 // name is a Cow<str>, which means it can do 0 copy string deserialization
@@ -77,6 +96,10 @@ impl RabbitConsumer for MyMessageConsumer {
 
         info!("got message #{msgs_received}: {msg:?} - name is borrowed = {is_borrowed} - total processed = {total_msgs_received}");
 
+        if let Some(feed) = &self.feed {
+            feed.publish(Self::MESSAGE_TYPE_HEADER, &msg);
+        }
+
         Ok(())
     }
 }
@@ -89,6 +112,7 @@ pub struct OtherMessageConsumer {
     received: AtomicUsize,
     received_all: Arc<AtomicUsize>,
     pupils: Mutex<Vec<Pupil>>,
+    feed: Option<ProcessedFeed>,
 }
 
 impl OtherMessageConsumer {
@@ -97,8 +121,15 @@ impl OtherMessageConsumer {
             received: Default::default(),
             received_all,
             pupils: Mutex::new(Vec::new()),
+            feed: None,
         }
     }
+
+    // publishes every message that's processed to the feed
+    pub fn with_feed(mut self, feed: ProcessedFeed) -> Self {
+        self.feed = Some(feed);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -145,6 +176,10 @@ impl RabbitConsumer for OtherMessageConsumer {
 
         tokio::time::sleep(Duration::from_secs(2)).await;
 
+        if let Some(feed) = &self.feed {
+            feed.publish(Self::MESSAGE_TYPE_HEADER, &msg);
+        }
+
         pupils.extend(msg.pupils);
 
         info!("there are a total {} pupils", pupils.len());
@@ -152,3 +187,106 @@ impl RabbitConsumer for OtherMessageConsumer {
         Ok(())
     }
 }
+
+// processes jobs, reporting their progress to the job store so they can be polled
+pub struct JobConsumer {
+    jobs: Arc<dyn JobStore>,
+}
+
+impl JobConsumer {
+    pub fn new(jobs: Arc<dyn JobStore>) -> JobConsumer {
+        JobConsumer { jobs }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobConsumerError {
+    #[error("failed to parse json: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("failed to update job: {0}")]
+    StoreError(#[from] JobStoreError),
+}
+
+impl ShouldRequeue for JobConsumerError {
+    fn should_requeue(&self) -> Requeue {
+        match self {
+            JobConsumerError::JsonError(_) => Requeue::No,
+            JobConsumerError::StoreError(err) => err.should_requeue(),
+        }
+    }
+}
+
+#[async_trait]
+impl RabbitConsumer for JobConsumer {
+    const MESSAGE_TYPE_HEADER: &'static str = JOB_MESSAGE_TYPE;
+
+    type Message<'a> = JobMessage;
+    type ConsumerError = JobConsumerError;
+
+    async fn process(&self, msg: Self::Message<'_>) -> Result<(), Self::ConsumerError> {
+        self.jobs.set_status(msg.id, JobStatus::Running).await?;
+
+        let status = match msg.message.pupils.len() {
+            0 => JobStatus::Failed {
+                error: "a job needs at least one pupil".to_string(),
+            },
+            pupils => JobStatus::Succeeded { pupils },
+        };
+        info!(job_id = %msg.id, ?status, "job finished");
+
+        self.jobs.set_status(msg.id, status).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::jobs::InMemoryJobStore;
+
+    fn job(pupils: Vec<Pupil>) -> JobMessage {
+        JobMessage {
+            id: Uuid::new_v4(),
+            message: OtherMessage {
+                school_age: SchoolAge::Primary,
+                pupils,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_report_their_outcome_to_the_store() {
+        let jobs = Arc::new(InMemoryJobStore::default());
+        let consumer = JobConsumer::new(jobs.clone());
+        let (ok, failed, missing) = (
+            job(vec![Pupil {
+                first_name: "jason".to_string(),
+                second_name: "mccullough".to_string(),
+            }]),
+            job(Vec::new()),
+            job(Vec::new()),
+        );
+        jobs.create(ok.id).await.unwrap();
+        jobs.create(failed.id).await.unwrap();
+
+        let contents = serde_json::to_vec(&ok).unwrap();
+        consumer.try_process(contents).await.unwrap();
+        let contents = serde_json::to_vec(&failed).unwrap();
+        consumer.try_process(contents).await.unwrap();
+        let contents = serde_json::to_vec(&missing).unwrap();
+        let err = consumer.try_process(contents).await.unwrap_err();
+
+        assert_eq!(
+            Some(JobStatus::Succeeded { pupils: 1 }),
+            jobs.status(ok.id).await.unwrap()
+        );
+        assert!(matches!(
+            jobs.status(failed.id).await.unwrap(),
+            Some(JobStatus::Failed { .. })
+        ));
+        // jobs nobody created can never succeed, so there's no point retrying them
+        assert_eq!(Requeue::No, err.should_requeue());
+    }
+}
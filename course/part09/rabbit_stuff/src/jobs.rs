@@ -0,0 +1,89 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    impls::OtherMessage,
+    rabbit::{Requeue, ShouldRequeue},
+};
+
+pub type JobId = Uuid;
+
+// published with JOB_MESSAGE_TYPE, the id is how the consumer reports back
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JobMessage {
+    pub id: JobId,
+    #[serde(flatten)]
+    pub message: OtherMessage,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded { pupils: usize },
+    Failed { error: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobStoreError {
+    #[error("job {0} doesn't exist")]
+    NotFound(JobId),
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+// a missing job will never turn up, other errors are probably the store being
+// temporarily unavailable so the message is tried again later
+impl ShouldRequeue for JobStoreError {
+    fn should_requeue(&self) -> Requeue {
+        match self {
+            JobStoreError::NotFound(_) => Requeue::No,
+            JobStoreError::Other(_) => Requeue::Yes,
+        }
+    }
+}
+
+// where job statuses are kept, shared between whatever creates jobs & the consumer
+// which processes them
+#[async_trait]
+pub trait JobStore: Send + Sync + 'static {
+    async fn create(&self, id: JobId) -> Result<(), JobStoreError>;
+
+    // errors with NotFound if the job was never created
+    async fn set_status(&self, id: JobId, status: JobStatus) -> Result<(), JobStoreError>;
+
+    async fn status(&self, id: JobId) -> Result<Option<JobStatus>, JobStoreError>;
+}
+
+// a JobStore which only works when jobs are created & consumed in the same process,
+// statuses are lost on restart
+#[derive(Debug, Default)]
+pub struct InMemoryJobStore {
+    jobs: RwLock<HashMap<JobId, JobStatus>>,
+}
+
+#[async_trait]
+impl JobStore for InMemoryJobStore {
+    async fn create(&self, id: JobId) -> Result<(), JobStoreError> {
+        self.jobs.write().unwrap().insert(id, JobStatus::Queued);
+        Ok(())
+    }
+
+    async fn set_status(&self, id: JobId, status: JobStatus) -> Result<(), JobStoreError> {
+        match self.jobs.write().unwrap().get_mut(&id) {
+            Some(current) => {
+                *current = status;
+                Ok(())
+            }
+            None => Err(JobStoreError::NotFound(id)),
+        }
+    }
+
+    async fn status(&self, id: JobId) -> Result<Option<JobStatus>, JobStoreError> {
+        Ok(self.jobs.read().unwrap().get(&id).cloned())
+    }
+}
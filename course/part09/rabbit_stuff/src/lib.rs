@@ -1,2 +1,6 @@
+pub mod feed;
 pub mod impls;
+pub mod jobs;
 pub mod rabbit;
+pub mod retry;
+pub mod topology;
@@ -2,7 +2,10 @@ use std::{
     fmt::Debug,
     future::Future,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
 };
 
@@ -11,15 +14,7 @@ use async_trait::async_trait;
 use futures::{future::BoxFuture, StreamExt};
 use lapin::{
     message::Delivery,
-    options::{
-        BasicAckOptions,
-        BasicConsumeOptions,
-        BasicNackOptions,
-        BasicPublishOptions,
-        ExchangeDeclareOptions,
-        QueueBindOptions,
-        QueueDeclareOptions,
-    },
+    options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions},
     protocol::constants::REPLY_SUCCESS,
     publisher_confirm::Confirmation,
     types::{AMQPValue::LongString, FieldTable},
@@ -27,25 +22,39 @@ use lapin::{
     Channel,
     Connection,
     ConnectionProperties,
+    ConnectionState,
     Consumer,
-    ExchangeKind,
 };
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use tokio::{select, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, info_span, instrument, Instrument};
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    retry::{self, DeadLetter, RepublishError, Retrier, RetryPolicy},
+    topology::Topology,
+};
 
 pub const QUEUE: &str = "queue-joseph";
 pub const EXCHANGE: &str = "exchange-joseph";
 const ROUTING: &str = "";
-const CONSUMER_TAG: &str = "joseph-consumer";
+// followed by a uuid, so every consumer has its own tag even when there are several
+// instances of a service consuming the same queue
+const CONSUMER_TAG_PREFIX: &str = "joseph-consumer";
 pub const MESSAGE_TYPE: &str = "msg-joseph";
 pub const MESSAGE_TYPE_2: &str = "msg-joseph-2";
+pub const JOB_MESSAGE_TYPE: &str = "msg-joseph-job";
+// carries the id of whatever published the message, e.g. an http request, so the
+// message's processing can be correlated with it in the logs
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
 
 pub struct Rabbit {
     conn: Connection,
     chan: Channel,
+    in_flight: Arc<AtomicUsize>,
+    retry_policy: RetryPolicy,
 }
 
 impl Rabbit {
@@ -55,35 +64,61 @@ impl Rabbit {
 
         let chan = conn.create_channel().await?;
 
-        Ok(Rabbit { conn, chan })
+        Ok(Rabbit {
+            conn,
+            chan,
+            in_flight: Default::default(),
+            retry_policy: RetryPolicy::default(),
+        })
+    }
+
+    // how consumers retry & dead letter the messages they fail to process
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
     }
 
     // ensure exchange + queue exist and bind them together
     pub async fn setup(&self) -> Result<(), lapin::Error> {
-        self.chan
-            .exchange_declare(
-                EXCHANGE,
-                ExchangeKind::Headers,
-                ExchangeDeclareOptions::default(),
-                FieldTable::default(),
-            )
-            .await?;
+        self.declare(&Topology::default()).await
+    }
 
-        self.chan
-            .queue_declare(QUEUE, QueueDeclareOptions::default(), FieldTable::default())
-            .await?;
+    // declares the exchanges, queues & bindings, anything that already exists with the
+    // same options is left alone
+    pub async fn declare(&self, topology: &Topology) -> Result<(), lapin::Error> {
+        topology.declare(&self.chan).await
+    }
 
-        self.chan
-            .queue_bind(
-                QUEUE,
-                EXCHANGE,
-                ROUTING,
-                QueueBindOptions::default(),
-                FieldTable::default(),
-            )
-            .await?;
+    // whether both the connection & channel are still usable, for health checks
+    pub fn is_connected(&self) -> bool {
+        self.conn.status().connected() && self.chan.status().connected()
+    }
 
-        Ok(())
+    pub fn connection_state(&self) -> ConnectionState {
+        self.conn.status().state()
+    }
+
+    // how many deliveries this rabbit's consumers are in the middle of processing
+    pub fn deliveries_in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    // up to `limit` messages from the dead letter queue, without removing them
+    pub async fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, lapin::Error> {
+        // a channel of its own, so nacking what it's got can't nack consumers' deliveries
+        let chan = self.conn.create_channel().await?;
+        let dead_letters = retry::inspect(&chan, &self.retry_policy.dead_letter_queue, limit).await;
+        chan.close(REPLY_SUCCESS, "thank you!").await?;
+        dead_letters
+    }
+
+    // republishes up to `limit` messages from the dead letter queue to the queues they
+    // were consumed from, returning how many were
+    pub async fn replay_dead_letters(&self, limit: usize) -> Result<usize, RepublishError> {
+        let chan = retry::confirming_channel(&self.conn).await?;
+        let replayed = retry::replay(&chan, &self.retry_policy.dead_letter_queue, limit).await;
+        chan.close(REPLY_SUCCESS, "thank you!").await?;
+        replayed
     }
 
     pub async fn close(&self) -> Result<(), lapin::Error> {
@@ -99,11 +134,27 @@ impl Rabbit {
         exchange: &str,
         message_type: &str,
         body: S,
+    ) -> Result<Confirmation, PublishError> {
+        self.publish_json_with_request_id(exchange, message_type, body, None)
+            .await
+    }
+
+    // the same as publish_json, with the request id added to the message's headers
+    // so that it's recorded on the span the message is processed in
+    pub async fn publish_json_with_request_id<S: Serialize>(
+        &self,
+        exchange: &str,
+        message_type: &str,
+        body: S,
+        request_id: Option<&str>,
     ) -> Result<Confirmation, PublishError> {
         let body = serde_json::to_string(&body)?;
         let mut headers = FieldTable::default();
         headers.insert("content-type".into(), LongString("application/json".into()));
         headers.insert("message_type".into(), LongString(message_type.into()));
+        if let Some(request_id) = request_id {
+            headers.insert(REQUEST_ID_HEADER.into(), LongString(request_id.into()));
+        }
         self.chan
             .basic_publish(
                 exchange,
@@ -120,26 +171,67 @@ impl Rabbit {
     // consumes messages from a queue and the delegator is responsible for
     // ensuring thew messages get consumed. in the provided implementations
     // this means by a RabbitConsumer if the message-type header matches
-    #[instrument(skip(self, rabbit_delegator, kill_signal))]
     pub async fn consume<D: RabbitDelegator>(
         &self,
         queue: &str,
         rabbit_delegator: D,
         kill_signal: CancellationToken,
     ) -> Result<JoinHandle<()>, lapin::Error> {
+        self.consume_with_abort(
+            queue,
+            rabbit_delegator,
+            kill_signal,
+            CancellationToken::new(),
+        )
+        .await
+    }
+
+    // the same as consume, except once the abort signal is cancelled any messages
+    // still being processed, or waiting for a worker, are nacked & requeued instead
+    // of being finished. the kill signal should be cancelled first. the retry policy's
+    // queues & dead letter exchange are declared before consuming
+    #[instrument(
+        name = "consume",
+        skip(self, rabbit_delegator, kill_signal, abort_signal)
+    )]
+    pub async fn consume_with_abort<D: RabbitDelegator>(
+        &self,
+        queue: &str,
+        rabbit_delegator: D,
+        kill_signal: CancellationToken,
+        abort_signal: CancellationToken,
+    ) -> Result<JoinHandle<()>, lapin::Error> {
+        self.declare(&self.retry_policy.topology(queue)).await?;
+
         let consumer = self
             .chan
             .basic_consume(
                 queue,
-                CONSUMER_TAG,
+                &format!("{CONSUMER_TAG_PREFIX}-{}", Uuid::new_v4()),
                 BasicConsumeOptions::default(),
                 FieldTable::default(),
             )
             .await?;
 
+        let signals = Signals {
+            kill: kill_signal,
+            abort: abort_signal,
+        };
+        let retrier = Retrier {
+            channel: self.chan.clone(),
+            publisher: retry::confirming_channel(&self.conn).await?,
+            policy: self.retry_policy.clone(),
+            queue: queue.to_string(),
+        };
         Ok(tokio::spawn(
-            run_consumer(rabbit_delegator, consumer, self.chan.clone(), kill_signal)
-                .in_current_span(),
+            run_consumer(
+                rabbit_delegator,
+                consumer,
+                retrier,
+                signals,
+                self.in_flight.clone(),
+            )
+            .in_current_span(),
         ))
     }
 }
@@ -152,16 +244,24 @@ pub enum PublishError {
     RabbitError(#[from] lapin::Error),
 }
 
+// kill stops new deliveries being consumed, abort gives up on the ones already consumed
+struct Signals {
+    kill: CancellationToken,
+    abort: CancellationToken,
+}
+
 async fn run_consumer<D: RabbitDelegator>(
     delegator: D,
     mut consumer: Consumer,
-    channel: Channel,
-    kill_signal: CancellationToken,
+    retrier: Retrier,
+    signals: Signals,
+    in_flight: Arc<AtomicUsize>,
 ) {
     let (sender, receiver) = async_channel::unbounded();
 
     // put delegator in an arc as we need to share it between the workers
     let delegator = Arc::new(delegator);
+    let retrier = Arc::new(retrier);
 
     // creates 10 workers for the queue & passes messages to them over a channel
     // there is a builtin lapin::Consumer::set_delegate, but i wanted to limit
@@ -169,17 +269,19 @@ async fn run_consumer<D: RabbitDelegator>(
     let handles = (0..10)
         .map(|i| {
             let span = info_span!("worker", "num" = i);
-            let channel = channel.clone();
+            let retrier = Arc::clone(&retrier);
             let delegator = Arc::clone(&delegator);
             let receiver = receiver.clone();
-            tokio::spawn(worker(channel, receiver, delegator).instrument(span))
+            let abort = signals.abort.clone();
+            let in_flight = in_flight.clone();
+            tokio::spawn(worker(retrier, receiver, delegator, abort, in_flight).instrument(span))
         })
         .collect::<Vec<_>>();
 
     loop {
         let delivery: Option<Result<Delivery, lapin::Error>> = select! {
             delivery = consumer.next() => delivery,
-            _ = kill_signal.cancelled() => break,
+            _ = signals.kill.cancelled() => break,
         };
 
         // None if consumer cancelled
@@ -216,40 +318,44 @@ async fn run_consumer<D: RabbitDelegator>(
 // a worker is responsible for processing a lapin::message::Delivery
 // via the delegator
 async fn worker<D: RabbitDelegator>(
-    channel: Channel,
+    retrier: Arc<Retrier>,
     mut receiver: Receiver<Delivery>,
     delegator: Arc<D>,
+    abort: CancellationToken,
+    in_flight: Arc<AtomicUsize>,
 ) {
+    let channel = &retrier.channel;
+
     // consumes from channel whilst it's not closed
     while let Some(delivery) = receiver.next().await {
-        let Some(header) = delivery
-            .properties
-            .headers()
-            .as_ref()
-            .and_then(|ft| ft.inner().get("message_type"))
-            .and_then(|message_type| message_type.as_long_string())
-            .map(|message_type| message_type.to_string())
-        else {
+        // hand anything still waiting for a worker straight back
+        if abort.is_cancelled() {
+            requeue(channel, delivery.delivery_tag).await;
+            continue;
+        }
+
+        let Some(header) = string_header(&delivery, "message_type") else {
             info!("unable to extract message_type header for {delivery:?}");
-            if let Err(err) = channel
-                .basic_nack(
+            retrier
+                .failed(
                     delivery.delivery_tag,
-                    BasicNackOptions {
-                        requeue: false,
-                        ..Default::default()
-                    },
+                    &delivery.properties,
+                    &delivery.data,
+                    Requeue::No,
+                    "no message_type header",
                 )
-                .await
-            {
-                error!("failed to nack msg: {}", err);
-            }
+                .await;
             continue;
         };
 
+        let request_id = string_header(&delivery, REQUEST_ID_HEADER);
         let delivery_tag = delivery.delivery_tag;
-        let contents = delivery.data;
+        // kept in case the message has to be republished to be retried
+        let contents = delivery.data.clone();
 
-        let span = info_span!("processing message", header);
+        // nested in the worker's span, so the request id is on everything logged
+        // whilst processing this message without leaking into the next one
+        let span = info_span!("processing message", header, request_id);
 
         // async{}.instrument(...).await is used as we cannot use
         // let _entered = span.enter() in async. .instrument allows us
@@ -257,11 +363,20 @@ async fn worker<D: RabbitDelegator>(
         // span.enter() would be entered for the entire time the future exists and not
         // just when it's running. have a look at the Future impl for the Instrumented
         // type to see how it's doing this
+        let _in_flight = InFlightDelivery::start(&in_flight);
         async {
-            let delegate_result = delegator.delegate(&header, contents).await;
+            let delegate_result = select! {
+                delegate_result = delegator.delegate(&header, contents) => delegate_result,
+                _ = abort.cancelled() => {
+                    warn!("gave up processing message {header} on shutdown, requeueing it");
+                    requeue(channel, delivery_tag).await;
+                    return;
+                }
+            };
 
-            // on success we ack, on failure we rack & requeue if the error allows for
-            //it (due to reasons such as transient failures etc)
+            // on success we ack, on failure the message is retried after a delay if the
+            // error allows for it (due to reasons such as transient failures etc), or
+            // dead lettered if it doesn't or it's been retried too many times
             match delegate_result {
                 Ok(_) => {
                     if let Err(err) = channel
@@ -272,20 +387,17 @@ async fn worker<D: RabbitDelegator>(
                     }
                 }
                 Err(err) => {
-                    let requeue = err.should_requeue().into();
-                    error!("failed to delegate message {header}: {err} - requeue = {requeue}");
-                    if let Err(err) = channel
-                        .basic_nack(
+                    let requeue = err.should_requeue();
+                    error!("failed to delegate message {header}: {err} - requeue = {requeue:?}");
+                    retrier
+                        .failed(
                             delivery_tag,
-                            BasicNackOptions {
-                                requeue,
-                                ..Default::default()
-                            },
+                            &delivery.properties,
+                            &delivery.data,
+                            requeue,
+                            &err.to_string(),
                         )
-                        .await
-                    {
-                        error!("failed to nack msg: {}", err);
-                    }
+                        .await;
                 }
             }
         }
@@ -296,6 +408,43 @@ async fn worker<D: RabbitDelegator>(
     info!("shutting down worker!")
 }
 
+pub(crate) async fn requeue(channel: &Channel, delivery_tag: u64) {
+    let options = BasicNackOptions {
+        requeue: true,
+        ..Default::default()
+    };
+    if let Err(err) = channel.basic_nack(delivery_tag, options).await {
+        error!("failed to nack msg: {}", err);
+    }
+}
+
+// counts a delivery as in flight until it's dropped
+struct InFlightDelivery(Arc<AtomicUsize>);
+
+impl InFlightDelivery {
+    fn start(in_flight: &Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight.clone())
+    }
+}
+
+impl Drop for InFlightDelivery {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// a string header from the delivery's properties, if it has one
+fn string_header(delivery: &Delivery, name: &str) -> Option<String> {
+    delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|ft| ft.inner().get(name))
+        .and_then(|value| value.as_long_string())
+        .map(|value| value.to_string())
+}
+
 // A trait for rabbit consumer/delegator errors that decides if a message should be
 // requeued or not. defaults to not requeueing
 pub trait ShouldRequeue {
@@ -0,0 +1,469 @@
+use std::time::Duration;
+
+use lapin::{
+    message::Delivery,
+    options::{
+        BasicAckOptions,
+        BasicGetOptions,
+        BasicNackOptions,
+        BasicPublishOptions,
+        ConfirmSelectOptions,
+    },
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, FieldTable, ShortString},
+    BasicProperties,
+    Channel,
+    Connection,
+};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    rabbit::{self, Requeue},
+    topology::{Argument, Arguments, Binding, Exchange, Kind, Queue, Topology},
+};
+
+// how many times a message has been retried, set when it's republished to a retry queue
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+// why the message last failed
+pub const ERROR_HEADER: &str = "x-error";
+// the queue a dead letter was consumed from, where it's replayed to
+pub const ORIGINAL_QUEUE_HEADER: &str = "x-original-queue";
+
+pub const DEAD_LETTER_EXCHANGE: &str = "exchange-joseph-dlx";
+pub const DEAD_LETTER_QUEUE: &str = "queue-joseph-dlq";
+
+// what happens to messages that fail to be processed. ones that can be requeued are
+// republished to a retry queue for each delay in turn, which sends them back to the
+// queue they came from once their ttl expires. after the last delay, or if they can't
+// be requeued, they go to the dead letter exchange with the error attached
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // how long to wait before each retry, there's one retry per delay
+    pub delays: Vec<Duration>,
+    pub dead_letter_exchange: String,
+    pub dead_letter_queue: String,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            delays: vec![
+                Duration::from_secs(1),
+                Duration::from_secs(5),
+                Duration::from_secs(30),
+            ],
+            dead_letter_exchange: DEAD_LETTER_EXCHANGE.to_string(),
+            dead_letter_queue: DEAD_LETTER_QUEUE.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Retry { retry_queue: String, retries: u32 },
+    DeadLetter,
+}
+
+impl RetryPolicy {
+    pub fn outcome(&self, queue: &str, requeue: Requeue, retries: u32) -> Outcome {
+        match (requeue, self.delays.get(retries as usize)) {
+            (Requeue::Yes, Some(delay)) => Outcome::Retry {
+                retry_queue: retry_queue(queue, *delay),
+                retries: retries + 1,
+            },
+            _ => Outcome::DeadLetter,
+        }
+    }
+
+    // the dead letter exchange & queue, plus the retry queues for `queue`
+    pub fn topology(&self, queue: &str) -> Topology {
+        let mut retry_queues = self
+            .delays
+            .iter()
+            .map(|delay| Queue {
+                name: retry_queue(queue, *delay),
+                durable: false,
+                exclusive: false,
+                auto_delete: false,
+                arguments: Arguments::from([
+                    (
+                        "x-message-ttl".to_string(),
+                        Argument::Int(delay.as_millis() as i64),
+                    ),
+                    // the default exchange, which routes by queue name
+                    (
+                        "x-dead-letter-exchange".to_string(),
+                        Argument::String(String::new()),
+                    ),
+                    (
+                        "x-dead-letter-routing-key".to_string(),
+                        Argument::String(queue.to_string()),
+                    ),
+                ]),
+            })
+            .collect::<Vec<_>>();
+        retry_queues.dedup();
+
+        let mut queues = vec![Queue {
+            name: self.dead_letter_queue.clone(),
+            durable: true,
+            exclusive: false,
+            auto_delete: false,
+            arguments: Arguments::new(),
+        }];
+        queues.extend(retry_queues);
+
+        Topology {
+            exchanges: vec![Exchange {
+                name: self.dead_letter_exchange.clone(),
+                kind: Kind::Fanout,
+                durable: true,
+                auto_delete: false,
+                arguments: Arguments::new(),
+            }],
+            queues,
+            bindings: vec![Binding {
+                queue: self.dead_letter_queue.clone(),
+                exchange: self.dead_letter_exchange.clone(),
+                routing_key: String::new(),
+                arguments: Arguments::new(),
+            }],
+        }
+    }
+}
+
+fn retry_queue(queue: &str, delay: Duration) -> String {
+    format!("{queue}.retry.{}ms", delay.as_millis())
+}
+
+fn retry_count(headers: &FieldTable) -> u32 {
+    let Some(value) = headers.inner().get(RETRY_COUNT_HEADER) else {
+        return 0;
+    };
+    value
+        .as_long_uint()
+        .or_else(|| value.as_long_long_int().and_then(|n| n.try_into().ok()))
+        .unwrap_or_default()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RepublishError {
+    #[error("rabbit operation failed: {0}")]
+    RabbitError(#[from] lapin::Error),
+    #[error("the broker nacked the message")]
+    Nacked,
+    #[error("nothing was bound to route the message to")]
+    Unroutable,
+    #[error("publisher confirms aren't enabled on the channel")]
+    NotConfirmed,
+}
+
+// a channel that the broker confirms every publish on, so a message is only acked once
+// its copy is definitely somewhere
+pub(crate) async fn confirming_channel(conn: &Connection) -> Result<Channel, lapin::Error> {
+    let channel = conn.create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    Ok(channel)
+}
+
+// what a worker uses to retry or dead letter the messages it fails to process
+#[derive(Debug, Clone)]
+pub(crate) struct Retrier {
+    // the channel the deliveries were consumed on, which they're acked on
+    pub(crate) channel: Channel,
+    // a confirming_channel, which they're republished on
+    pub(crate) publisher: Channel,
+    pub(crate) policy: RetryPolicy,
+    pub(crate) queue: String,
+}
+
+impl Retrier {
+    // acks the delivery once the broker has confirmed it's been republished to a retry
+    // queue or the dead letter exchange. if that fails it's requeued, as losing it
+    // would be worse
+    pub(crate) async fn failed(
+        &self,
+        delivery_tag: u64,
+        properties: &BasicProperties,
+        data: &[u8],
+        requeue: Requeue,
+        err: &str,
+    ) {
+        let mut headers = properties.headers().clone().unwrap_or_default();
+        let retries = retry_count(&headers);
+        headers.insert(ERROR_HEADER.into(), AMQPValue::LongString(err.into()));
+
+        let (exchange, routing_key) = match self.policy.outcome(&self.queue, requeue, retries) {
+            Outcome::Retry {
+                retry_queue,
+                retries,
+            } => {
+                info!(retries, retry_queue, "retrying message");
+                headers.insert(RETRY_COUNT_HEADER.into(), AMQPValue::LongUInt(retries));
+                (String::new(), retry_queue)
+            }
+            Outcome::DeadLetter => {
+                warn!(retries, "dead lettering message");
+                headers.insert(
+                    ORIGINAL_QUEUE_HEADER.into(),
+                    AMQPValue::LongString(self.queue.as_str().into()),
+                );
+                (self.policy.dead_letter_exchange.clone(), self.queue.clone())
+            }
+        };
+
+        let properties = properties.clone().with_headers(headers);
+        let published = publish(&self.publisher, &exchange, &routing_key, data, properties).await;
+        if let Err(err) = published {
+            error!("failed to republish msg, requeueing it instead: {err}");
+            rabbit::requeue(&self.channel, delivery_tag).await;
+            return;
+        }
+        if let Err(err) = self
+            .channel
+            .basic_ack(delivery_tag, BasicAckOptions::default())
+            .await
+        {
+            error!("failed to ack msg: {}", err);
+        }
+    }
+}
+
+// mandatory, so a message that can't be routed anywhere is returned rather than dropped
+async fn publish(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    data: &[u8],
+    properties: BasicProperties,
+) -> Result<(), RepublishError> {
+    let options = BasicPublishOptions {
+        mandatory: true,
+        ..Default::default()
+    };
+    let confirmation = channel
+        .basic_publish(exchange, routing_key, options, data, properties)
+        .await?
+        .await?;
+    confirmed(confirmation)
+}
+
+fn confirmed(confirmation: Confirmation) -> Result<(), RepublishError> {
+    match confirmation {
+        Confirmation::Ack(None) => Ok(()),
+        // acked, but with the message returned to us
+        Confirmation::Ack(Some(_)) => Err(RepublishError::Unroutable),
+        Confirmation::Nack(_) => Err(RepublishError::Nacked),
+        Confirmation::NotRequested => Err(RepublishError::NotConfirmed),
+    }
+}
+
+// a message from the dead letter queue
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeadLetter {
+    pub message_type: Option<String>,
+    pub original_queue: Option<String>,
+    pub retries: u32,
+    pub error: Option<String>,
+    pub body: String,
+}
+
+impl From<&Delivery> for DeadLetter {
+    fn from(delivery: &Delivery) -> Self {
+        let headers = delivery.properties.headers().clone().unwrap_or_default();
+        let string = |name: &str| {
+            headers
+                .inner()
+                .get(name)
+                .and_then(|value| value.as_long_string())
+                .map(|value| value.to_string())
+        };
+        Self {
+            message_type: string("message_type"),
+            original_queue: string(ORIGINAL_QUEUE_HEADER),
+            retries: retry_count(&headers),
+            error: string(ERROR_HEADER),
+            body: String::from_utf8_lossy(&delivery.data).into_owned(),
+        }
+    }
+}
+
+// up to `limit` messages from the front of the dead letter queue, which are all left
+// on it
+pub(crate) async fn inspect(
+    channel: &Channel,
+    dead_letter_queue: &str,
+    limit: usize,
+) -> Result<Vec<DeadLetter>, lapin::Error> {
+    let mut dead_letters = Vec::new();
+    let mut last_tag = None;
+    while dead_letters.len() < limit {
+        let Some(message) = channel
+            .basic_get(dead_letter_queue, BasicGetOptions::default())
+            .await?
+        else {
+            break;
+        };
+        dead_letters.push(DeadLetter::from(&message.delivery));
+        last_tag = Some(message.delivery.delivery_tag);
+    }
+
+    // only once they've all been got, otherwise the first would be got again
+    if let Some(last_tag) = last_tag {
+        channel
+            .basic_nack(
+                last_tag,
+                BasicNackOptions {
+                    multiple: true,
+                    requeue: true,
+                },
+            )
+            .await?;
+    }
+    Ok(dead_letters)
+}
+
+// republishes up to `limit` dead letters to the queues they came from, with their retry
+// count reset. returns how many were replayed, any without an original queue are left.
+// the channel should be a confirming_channel
+pub(crate) async fn replay(
+    channel: &Channel,
+    dead_letter_queue: &str,
+    limit: usize,
+) -> Result<usize, RepublishError> {
+    let mut replayed = 0;
+    let mut last_skipped = None;
+    for _ in 0..limit {
+        let Some(message) = channel
+            .basic_get(dead_letter_queue, BasicGetOptions::default())
+            .await?
+        else {
+            break;
+        };
+        let delivery = message.delivery;
+
+        let headers = delivery.properties.headers().clone().unwrap_or_default();
+        let original_queue = headers
+            .inner()
+            .get(ORIGINAL_QUEUE_HEADER)
+            .and_then(|value| value.as_long_string())
+            .map(|value| value.to_string());
+        let Some(original_queue) = original_queue else {
+            warn!(
+                delivery_tag = delivery.delivery_tag,
+                "dead letter has no original queue, leaving it"
+            );
+            last_skipped = Some(delivery.delivery_tag);
+            continue;
+        };
+
+        // as if it had never failed
+        let mut replayed_headers = FieldTable::default();
+        for (name, value) in headers.inner() {
+            if ![RETRY_COUNT_HEADER, ERROR_HEADER, ORIGINAL_QUEUE_HEADER].contains(&name.as_str()) {
+                replayed_headers.insert(ShortString::from(name.as_str()), value.clone());
+            }
+        }
+
+        let properties = delivery.properties.clone().with_headers(replayed_headers);
+        publish(channel, "", &original_queue, &delivery.data, properties).await?;
+        channel
+            .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+            .await?;
+        replayed += 1;
+    }
+
+    if let Some(last_skipped) = last_skipped {
+        channel
+            .basic_nack(
+                last_skipped,
+                BasicNackOptions {
+                    multiple: true,
+                    requeue: true,
+                },
+            )
+            .await?;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requeueable_failures_are_retried_until_the_delays_run_out() {
+        let policy = RetryPolicy {
+            delays: vec![Duration::from_secs(1), Duration::from_secs(5)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Outcome::Retry {
+                retry_queue: "queue-joseph.retry.1000ms".to_string(),
+                retries: 1
+            },
+            policy.outcome("queue-joseph", Requeue::Yes, 0)
+        );
+        assert_eq!(
+            Outcome::Retry {
+                retry_queue: "queue-joseph.retry.5000ms".to_string(),
+                retries: 2
+            },
+            policy.outcome("queue-joseph", Requeue::Yes, 1)
+        );
+        assert_eq!(
+            Outcome::DeadLetter,
+            policy.outcome("queue-joseph", Requeue::Yes, 2)
+        );
+        assert_eq!(
+            Outcome::DeadLetter,
+            policy.outcome("queue-joseph", Requeue::No, 0)
+        );
+    }
+
+    #[test]
+    fn retry_counts_are_read_whatever_int_they_were_published_as() {
+        let mut headers = FieldTable::default();
+        assert_eq!(0, retry_count(&headers));
+        headers.insert(RETRY_COUNT_HEADER.into(), AMQPValue::LongUInt(2));
+        assert_eq!(2, retry_count(&headers));
+        headers.insert(RETRY_COUNT_HEADER.into(), AMQPValue::LongLongInt(3));
+        assert_eq!(3, retry_count(&headers));
+    }
+
+    #[test]
+    fn only_confirmed_publishes_succeed() {
+        assert!(confirmed(Confirmation::Ack(None)).is_ok());
+        assert!(matches!(
+            confirmed(Confirmation::Nack(None)),
+            Err(RepublishError::Nacked)
+        ));
+        assert!(matches!(
+            confirmed(Confirmation::NotRequested),
+            Err(RepublishError::NotConfirmed)
+        ));
+    }
+
+    #[test]
+    fn retry_queues_send_expired_messages_back() {
+        let topology = RetryPolicy::default().topology("queue-joseph");
+
+        assert_eq!(DEAD_LETTER_QUEUE, topology.queues[0].name);
+        assert_eq!(DEAD_LETTER_EXCHANGE, topology.bindings[0].exchange);
+
+        let retry = &topology.queues[1];
+        assert_eq!("queue-joseph.retry.1000ms", retry.name);
+        assert_eq!(
+            Some(&Argument::Int(1000)),
+            retry.arguments.get("x-message-ttl")
+        );
+        assert_eq!(
+            Some(&Argument::String("queue-joseph".to_string())),
+            retry.arguments.get("x-dead-letter-routing-key")
+        );
+    }
+}
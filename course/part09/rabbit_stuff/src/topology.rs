@@ -0,0 +1,289 @@
+use std::{collections::BTreeMap, path::Path};
+
+use lapin::{
+    options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    types::{AMQPValue, FieldTable, ShortString},
+    Channel,
+    ExchangeKind,
+};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::rabbit::{EXCHANGE, QUEUE};
+
+// the exchanges, queues & bindings a service needs, e.g.
+//
+//     [[exchanges]]
+//     name = "exchange-joseph"
+//     kind = "headers"
+//
+//     [[queues]]
+//     name = "queue-joseph-jobs"
+//     durable = true
+//     arguments = { "x-max-length" = 1000 }
+//
+//     [[bindings]]
+//     queue = "queue-joseph-jobs"
+//     exchange = "exchange-joseph"
+//     arguments = { "x-match" = "all", message_type = "msg-joseph-job" }
+//
+// declaring is idempotent, so every service can declare everything it uses, as long as
+// they all agree on the options of anything they share
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    #[serde(default)]
+    pub exchanges: Vec<Exchange>,
+    #[serde(default)]
+    pub queues: Vec<Queue>,
+    #[serde(default)]
+    pub bindings: Vec<Binding>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Exchange {
+    pub name: String,
+    pub kind: Kind,
+    #[serde(default)]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    #[serde(default)]
+    pub arguments: Arguments,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Direct,
+    Fanout,
+    Topic,
+    Headers,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Queue {
+    pub name: String,
+    #[serde(default)]
+    pub durable: bool,
+    #[serde(default)]
+    pub exclusive: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    // e.g. x-message-ttl or x-dead-letter-exchange
+    #[serde(default)]
+    pub arguments: Arguments,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    pub queue: String,
+    pub exchange: String,
+    #[serde(default)]
+    pub routing_key: String,
+    // for headers exchanges, the headers to match & x-match = "all" or "any"
+    #[serde(default)]
+    pub arguments: Arguments,
+}
+
+pub type Arguments = BTreeMap<String, Argument>;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Argument {
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TopologyError {
+    #[error("failed to read topology: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("invalid topology: {0}")]
+    Parse(#[from] toml::de::Error),
+}
+
+impl Topology {
+    pub fn from_toml(toml: &str) -> Result<Self, TopologyError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TopologyError> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    // declares everything, exchanges then queues then bindings so that whatever's
+    // being bound already exists
+    pub async fn declare(&self, chan: &Channel) -> Result<(), lapin::Error> {
+        for exchange in &self.exchanges {
+            let options = ExchangeDeclareOptions {
+                durable: exchange.durable,
+                auto_delete: exchange.auto_delete,
+                ..Default::default()
+            };
+            chan.exchange_declare(
+                &exchange.name,
+                exchange.kind.into(),
+                options,
+                field_table(&exchange.arguments),
+            )
+            .await?;
+        }
+
+        for queue in &self.queues {
+            let options = QueueDeclareOptions {
+                durable: queue.durable,
+                exclusive: queue.exclusive,
+                auto_delete: queue.auto_delete,
+                ..Default::default()
+            };
+            chan.queue_declare(&queue.name, options, field_table(&queue.arguments))
+                .await?;
+        }
+
+        for binding in &self.bindings {
+            chan.queue_bind(
+                &binding.queue,
+                &binding.exchange,
+                &binding.routing_key,
+                QueueBindOptions::default(),
+                field_table(&binding.arguments),
+            )
+            .await?;
+        }
+
+        info!(
+            exchanges = self.exchanges.len(),
+            queues = self.queues.len(),
+            bindings = self.bindings.len(),
+            "declared topology"
+        );
+        Ok(())
+    }
+}
+
+// what Rabbit::setup declares: a headers exchange with one queue that gets everything
+// sent to it
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            exchanges: vec![Exchange {
+                name: EXCHANGE.to_string(),
+                kind: Kind::Headers,
+                durable: false,
+                auto_delete: false,
+                arguments: Arguments::new(),
+            }],
+            queues: vec![Queue {
+                name: QUEUE.to_string(),
+                durable: false,
+                exclusive: false,
+                auto_delete: false,
+                arguments: Arguments::new(),
+            }],
+            bindings: vec![Binding {
+                queue: QUEUE.to_string(),
+                exchange: EXCHANGE.to_string(),
+                routing_key: String::new(),
+                arguments: Arguments::new(),
+            }],
+        }
+    }
+}
+
+impl From<Kind> for ExchangeKind {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Direct => ExchangeKind::Direct,
+            Kind::Fanout => ExchangeKind::Fanout,
+            Kind::Topic => ExchangeKind::Topic,
+            Kind::Headers => ExchangeKind::Headers,
+        }
+    }
+}
+
+fn field_table(arguments: &Arguments) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (name, argument) in arguments {
+        let value = match argument {
+            Argument::Bool(b) => AMQPValue::Boolean(*b),
+            Argument::Int(i) => AMQPValue::LongLongInt(*i),
+            Argument::String(s) => AMQPValue::LongString(s.as_str().into()),
+        };
+        table.insert(ShortString::from(name.as_str()), value);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings_match_on_headers_from_toml() {
+        let topology = Topology::from_toml(
+            r#"
+            [[exchanges]]
+            name = "exchange-joseph"
+            kind = "headers"
+
+            [[queues]]
+            name = "queue-joseph-jobs"
+            durable = true
+            arguments = { "x-max-length" = 1000 }
+
+            [[bindings]]
+            queue = "queue-joseph-jobs"
+            exchange = "exchange-joseph"
+            arguments = { "x-match" = "all", message_type = "msg-joseph-job" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(Kind::Headers, topology.exchanges[0].kind);
+        assert!(topology.queues[0].durable);
+        assert_eq!(
+            Some(&AMQPValue::LongLongInt(1000)),
+            field_table(&topology.queues[0].arguments)
+                .inner()
+                .get("x-max-length")
+        );
+
+        let binding = field_table(&topology.bindings[0].arguments);
+        assert_eq!(
+            Some(&AMQPValue::LongString("all".into())),
+            binding.inner().get("x-match")
+        );
+        assert_eq!(
+            Some(&AMQPValue::LongString("msg-joseph-job".into())),
+            binding.inner().get("message_type")
+        );
+    }
+
+    #[test]
+    fn the_example_topology_includes_the_default() {
+        let example =
+            Topology::load(concat!(env!("CARGO_MANIFEST_DIR"), "/topology.toml")).unwrap();
+        let default = Topology::default();
+        assert_eq!(default.exchanges, example.exchanges);
+        assert!(example.queues.starts_with(&default.queues));
+        assert!(example.bindings.starts_with(&default.bindings));
+    }
+
+    #[test]
+    fn typos_are_rejected() {
+        let err = Topology::from_toml(
+            r#"
+            [[queues]]
+            name = "queue-joseph"
+            durabel = true
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("durabel"), "{err}");
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    response::{IntoResponse, Response},
+};
+use axum_macros::{FromRequest, FromRequestParts};
+use http::StatusCode;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::validation::FieldError;
+
+// the machine readable part of an error, these are part of the api so existing codes
+// must never be renamed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidJson,
+    MissingJsonContentType,
+    InvalidBody,
+    InvalidPath,
+    InvalidQuery,
+    DivideByZero,
+    PublishFailed,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Overloaded,
+    RateLimited,
+    ValidationFailed,
+    PayloadTooLarge,
+    InjectedFault,
+    Internal,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the same as what's serialized
+        let code = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        f.write_str(code.as_str().unwrap_or_default())
+    }
+}
+
+// the error every route responds with, rendered as
+// `{"error": {"code": "divide_by_zero", "message": "denominator cannot be zero"}}`
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{code}: {message}")]
+pub struct ApiError {
+    status: StatusCode,
+    code: ErrorCode,
+    message: String,
+    details: Vec<FieldError>,
+}
+
+// what an ApiError is serialized as
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiErrorEnvelope {
+    error: ApiErrorBody,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiErrorBody {
+    code: ErrorCode,
+    message: String,
+    // which fields were invalid, for validation errors
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    details: Vec<FieldError>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn with_details(mut self, details: Vec<FieldError>) -> Self {
+        self.details = details;
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    // for when there's somewhere other than the response to put the error, such as a
+    // line of a streamed response
+    pub fn into_body(self) -> ApiErrorBody {
+        ApiErrorBody {
+            code: self.code,
+            message: self.message,
+            details: self.details,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status;
+        let envelope = ApiErrorEnvelope {
+            error: self.into_body(),
+        };
+        (status, axum::Json(envelope)).into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let code = match rejection {
+            JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_) => {
+                ErrorCode::InvalidJson
+            }
+            JsonRejection::MissingJsonContentType(_) => ErrorCode::MissingJsonContentType,
+            // from DefaultBodyLimit
+            _ if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            _ => ErrorCode::InvalidBody,
+        };
+        Self::new(rejection.status(), code, rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(
+            rejection.status(),
+            ErrorCode::InvalidPath,
+            rejection.body_text(),
+        )
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(
+            rejection.status(),
+            ErrorCode::InvalidQuery,
+            rejection.body_text(),
+        )
+    }
+}
+
+// axum's Path, except rejections are turned into an ApiError
+#[derive(Debug, Clone, Copy, Default, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct Path<T>(pub T);
+
+// axum's Query, except rejections are turned into an ApiError
+#[derive(Debug, Clone, Copy, Default, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
+
+// axum's Json, except rejections are turned into an ApiError
+#[derive(Debug, Clone, Copy, Default, FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Router};
+    use http::{header::CONTENT_TYPE, Request};
+    use serde::Deserialize;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Numbers {
+        #[allow(dead_code)]
+        numerator: isize,
+    }
+
+    async fn send(content_type: &str, body: &'static str) -> (StatusCode, String) {
+        let router: Router = Router::new().route("/", post(|_: Json<Numbers>| async {}));
+        let req = Request::post("/")
+            .header(CONTENT_TYPE, content_type)
+            .body(hyper::Body::from(body))
+            .unwrap();
+
+        let res = router.oneshot(req).await.unwrap();
+        assert_eq!("application/json", res.headers()[CONTENT_TYPE]);
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn json_rejections_use_the_envelope() {
+        let (status, body) = send("application/json", "{").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert!(
+            body.starts_with(r#"{"error":{"code":"invalid_json","#),
+            "{body}"
+        );
+
+        let (status, body) = send("application/json", r#"{"numerator": "one"}"#).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert!(body.contains(r#""code":"invalid_json""#), "{body}");
+
+        let (status, body) = send("text/plain", "{}").await;
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, status);
+        assert!(
+            body.contains(r#""code":"missing_json_content_type""#),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn codes_display_as_they_serialize() {
+        let err = ApiError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::DivideByZero,
+            "denominator cannot be zero",
+        );
+        assert_eq!(
+            "divide_by_zero: denominator cannot be zero",
+            err.to_string()
+        );
+    }
+}
@@ -1,37 +1,84 @@
-use std::future::{ready, Ready};
+use std::{
+    future::{ready, Ready},
+    time::Duration,
+};
 
-use bytes::Bytes;
 use futures::{stream::FuturesUnordered, StreamExt};
 use http::{Response, StatusCode, Uri};
-use http_body::Full;
-use hyper::{Client, Request};
-use tower::{retry::Policy, Service, ServiceBuilder};
+use hyper::{Body, Client, Request};
+use tower::{
+    retry::{budget::Budget, Policy},
+    Service,
+    ServiceBuilder,
+};
 use tracing::info;
 
-use axum_stuff::tower_stuff::{backoff_strategies::*, BackoffLayer};
+use axum_stuff::tower_stuff::{
+    backoff_strategies::*,
+    try_clone_request,
+    BackoffLayer,
+    BufferedBodyLayer,
+    CircuitBreakerLayer,
+    HttpFailureClassifier,
+    HttpRetryAfter,
+    ReplayBody,
+    TryCloneBody,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     axum_stuff::tracing_config::init()?;
 
     let mut http_service = ServiceBuilder::new()
-        .layer(BackoffLayer::new(
-            HttpPolicy::new(5),
-            ExponentialBackoffStrategy,
-        ))
+        // streamed bodies of up to 64KiB are buffered so that they can be retried
+        .layer(BufferedBodyLayer::new(64 * 1024))
+        .layer(
+            BackoffLayer::new(
+                HttpPolicy::new(5),
+                // jittered so that many clients backing off at once don't retry in lockstep
+                MaxBackoff::new(
+                    FullJitter::new(MultipliedBackoff::new(ExponentialBackoffStrategy, 50)),
+                    Duration::from_secs(2),
+                ),
+            )
+            // at most 20% extra load from retries & no more than 5s for any one call
+            .with_retry_budget(Budget::new(Duration::from_secs(10), 1, 0.2))
+            .with_deadline(Duration::from_secs(5))
+            // the server sends Retry-After when it sheds load, capped at the deadline as
+            // there's no point waiting any longer than that
+            .with_retry_after(HttpRetryAfter::new(Duration::from_secs(5))),
+        )
+        // each attempt counts towards the circuit, so once the server is clearly
+        // struggling the remaining retries fail fast
+        .layer(
+            CircuitBreakerLayer::builder(HttpFailureClassifier)
+                .minimum_calls(4)
+                .open_duration(Duration::from_secs(3))
+                .build(),
+        )
         .service(
             Client::builder()
                 .http2_only(true)
-                .build_http::<Full<Bytes>>(),
+                .build_http::<ReplayBody<Body>>(),
         );
 
     let uri = Uri::try_from("http://localhost:25565/hello")?;
     let mut f = FuturesUnordered::new();
     for _ in 0..2 {
-        let req = Request::get(uri.clone()).body(Full::new(Bytes::new()))?;
+        let req = Request::get(uri.clone()).body(Body::empty())?;
         f.push(http_service.call(req));
     }
 
+    // a streamed json body, which wouldn't be retryable without BufferedBodyLayer
+    let body = Body::wrap_stream(futures::stream::iter([
+        Ok::<_, std::io::Error>(r#"{"numerator": 13, "#),
+        Ok(r#""denominator": 5}"#),
+    ]));
+    let req = Request::get(Uri::try_from("http://localhost:25565/numbers/divide2")?)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body)?;
+    f.push(http_service.call(req));
+
     while let Some(f) = f.next().await {
         let f = f?;
         info!("got resp: {f:?}");
@@ -53,7 +100,7 @@ impl HttpPolicy {
 
 impl<Req, Res, Err> Policy<Request<Req>, Response<Res>, Err> for HttpPolicy
 where
-    Req: Clone,
+    Req: TryCloneBody,
 {
     type Future = Ready<Self>;
 
@@ -74,13 +121,6 @@ where
     }
 
     fn clone_request(&self, req: &Request<Req>) -> Option<Request<Req>> {
-        let mut request = Request::builder()
-            .method(req.method().clone())
-            .uri(req.uri().clone())
-            .version(req.version())
-            .body(req.body().clone())
-            .ok()?;
-        *request.headers_mut() = req.headers().clone();
-        Some(request)
+        try_clone_request(req)
     }
 }
@@ -1,10 +1,12 @@
 use futures::{stream::FuturesUnordered, StreamExt};
 use hyper::{Body, Client, Request, Uri};
 use std::time::Duration;
-use tower::Service;
-use tower_http::decompression::Decompression;
+use tower::{ServiceBuilder, ServiceExt};
+use tower_http::decompression::DecompressionLayer;
 use tracing::{debug, info};
 
+use axum_stuff::tower_stuff::{CircuitBreakerError, CircuitBreakerLayer, HttpFailureClassifier};
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     axum_stuff::tracing_config::init()?;
@@ -13,16 +15,25 @@ async fn main() -> anyhow::Result<()> {
 
     // let http_client = Client::builder().http2_only(true).build_http();
 
+    // shared between runs so that the server isn't hammered whilst it's down
+    let circuit_breaker = CircuitBreakerLayer::builder(HttpFailureClassifier)
+        .failure_rate(0.5)
+        .open_duration(Duration::from_secs(5))
+        .build();
+
     for i in 1.. {
         let http_client = Client::new();
-        let compression_client = Decompression::new(&http_client);
+        let compression_client = ServiceBuilder::new()
+            .layer(circuit_breaker.clone())
+            .layer(DecompressionLayer::new())
+            .service(&http_client);
 
         info!("start of run {i}");
 
         let mut futs = FuturesUnordered::new();
 
         for _ in 0..10 {
-            let mut compression_client = compression_client.clone();
+            let compression_client = compression_client.clone();
 
             let fut = async move {
                 let request = Request::builder()
@@ -30,7 +41,14 @@ async fn main() -> anyhow::Result<()> {
                     .uri("http://localhost:25565/endpoint".parse::<Uri>()?)
                     .body(Body::empty())?;
 
-                let resp = compression_client.call(request).await?;
+                let resp = match compression_client.oneshot(request).await {
+                    Ok(resp) => resp,
+                    Err(CircuitBreakerError::Open(err)) => {
+                        info!("not calling the server: {err}");
+                        return Ok(());
+                    }
+                    Err(CircuitBreakerError::Inner(err)) => return Err(err.into()),
+                };
 
                 if !resp.status().is_success() {
                     info!(code = ?resp.status(), "hit a bad response");
@@ -1,78 +1,248 @@
 use std::{
-    net::{SocketAddr, TcpListener},
+    net::TcpListener,
     sync::{atomic::AtomicUsize, Arc},
     time::Duration,
 };
 
 use axum::Server;
-use tokio_util::sync::CancellationToken;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::timeout,
+};
 use tower::ServiceBuilder;
-use tracing::info;
+use tracing::{info, warn};
 
 use axum_stuff::{
-    routers::service,
-    tower_stuff::{ConnectionLimitLayer, NewConnSpanMakeServiceLayer},
+    health::Health,
+    metrics::Metrics,
+    routers::{service, ServiceDeps},
+    server_config::{Limits, ServerConfig},
+    shutdown::{wait_for_signal, InFlight, ShutdownCoordinator},
+    tls::Tls,
+    tower_stuff::{
+        ClientRateLimitLayer,
+        ConnectionLimitLayer,
+        InMemoryRateLimitStore,
+        NewConnSpanMakeServiceLayer,
+        PanicCaptureLayer,
+        ReloadableRateLimitLayer,
+    },
 };
 use rabbit_stuff::{
-    impls::{MyMessageConsumer, OtherMessageConsumer},
+    feed::ProcessedFeed,
+    impls::{JobConsumer, MyMessageConsumer, OtherMessageConsumer},
+    jobs::{InMemoryJobStore, JobStore},
     rabbit::{Rabbit, QUEUE},
+    topology::Topology,
 };
 
+// how many processed messages a /messages subscriber can fall behind by before it
+// starts missing them
+const PROCESSED_FEED_CAPACITY: usize = 64;
+
+// how long readiness fails for before connections start being drained
+const READINESS_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// how long aborted rabbit workers get to requeue what they were processing, & aborted
+// connections get to be dropped
+const ABORT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     axum_stuff::tracing_config::init()?;
 
     info!("hello!");
-    let rabbit = Rabbit::new("amqp://localhost:5672").await?;
-    rabbit.setup().await?;
+    let config = ServerConfig::load()?;
+    info!(?config, "loaded config");
+    let limits = Limits::new(&config.limits);
+
+    let rabbit = Arc::new(Rabbit::new(&config.amqp_url).await?);
+    match &config.rabbit_topology {
+        Some(path) => rabbit.declare(&Topology::load(path)?).await?,
+        None => rabbit.setup().await?,
+    }
 
-    let cancel = CancellationToken::new();
+    let shutdown =
+        ShutdownCoordinator::new(Duration::from_millis(config.shutdown.drain_timeout_ms));
 
     let global_counter = Arc::new(AtomicUsize::new(0));
 
-    let rabbit_consumer_handle = rabbit
-        .consume(
+    let feed = ProcessedFeed::new(PROCESSED_FEED_CAPACITY);
+
+    // jobs are consumed by this process too, so they can be kept in memory
+    let jobs: Arc<dyn JobStore> = Arc::new(InMemoryJobStore::default());
+
+    let mut rabbit_consumer_handle = rabbit
+        .consume_with_abort(
             QUEUE,
             (
-                MyMessageConsumer::new(global_counter.clone()),
-                OtherMessageConsumer::new(global_counter),
+                MyMessageConsumer::new(global_counter.clone()).with_feed(feed.clone()),
+                OtherMessageConsumer::new(global_counter).with_feed(feed.clone()),
+                JobConsumer::new(jobs.clone()),
             ),
-            cancel.clone(),
+            shutdown.stop_token(),
+            shutdown.abort_token(),
         )
         .await?;
 
     info!("set up rabbit connection!");
 
-    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 25565)))?;
-
-    let addr = listener.local_addr()?;
-    info!("accepting requests on  {addr:?}");
-    let server = Server::from_tcp(listener)?
-        .serve(
-            // service(),
-            ServiceBuilder::new()
-                .load_shed()
-                .rate_limit(87654321, Duration::from_secs(1))
-                .layer(ConnectionLimitLayer::new(5))
-                .layer(NewConnSpanMakeServiceLayer)
-                .service(service(rabbit)),
+    let health = Health::new(
+        rabbit.clone(),
+        rabbit_consumer_handle.abort_handle(),
+        limits.concurrency_limit.semaphore(),
+    );
+
+    // limit how many connections any one client can have, and idle keep-alive
+    // connections don't get to hold on to their permit forever. when full,
+    // new connections wait a little then get told to come back later
+    let connection_limit = ConnectionLimitLayer::resizable(limits.max_connections.clone())
+        .per_ip(config.limits.max_connections_per_ip, 1024)
+        .idle_timeout(Duration::from_secs(30))
+        .max_lifetime(Duration::from_secs(10 * 60))
+        .queue(Duration::from_secs(2), Duration::from_secs(1), 256);
+    // panics are only captured around the handlers that are expected to panic
+    let panic_capture = PanicCaptureLayer::default().with_backtrace();
+    let metrics = Metrics::new(connection_limit.metrics(), panic_capture.clone());
+
+    let rate_limit = config
+        .rate_limit
+        .routes
+        .iter()
+        .fold(
+            ClientRateLimitLayer::new(
+                Arc::new(InMemoryRateLimitStore::default()),
+                config.rate_limit.default.quota(),
+            ),
+            |layer, route| layer.route(&route.route, route.rate.quota()),
         )
-        .with_graceful_shutdown({
-            let shutdown = cancel.clone();
-            async move { shutdown.cancelled().await }
-        });
-
-    let server_handle = tokio::spawn(server);
-
-    let _ = tokio::signal::ctrl_c().await;
-
-    cancel.cancel();
+        .api_keys(config.rate_limit.api_keys.iter().cloned());
 
-    server_handle.await??;
+    let listener = TcpListener::bind(config.listen_addr)?;
 
-    rabbit_consumer_handle.await?;
+    let addr = listener.local_addr()?;
+    let make_service = ServiceBuilder::new()
+        .load_shed()
+        .layer(ReloadableRateLimitLayer::new(
+            limits.connection_rate.clone(),
+        ))
+        .layer(connection_limit)
+        .layer(NewConnSpanMakeServiceLayer)
+        .service(service(
+            ServiceDeps {
+                rabbit: rabbit.clone(),
+                concurrency_limit: limits.concurrency_limit.semaphore(),
+                health: health.clone(),
+                metrics: metrics.clone(),
+                jobs,
+                feed,
+                rate_limit,
+                panic_capture,
+                stop: shutdown.stop_token(),
+            },
+            &config,
+        ));
+    let stopped = {
+        let stop = shutdown.stop_token();
+        async move { stop.cancelled().await }
+    };
+
+    let tls = config.tls.as_ref().map(Tls::load).transpose()?;
+    let mut server_handle = match &tls {
+        Some(tls) => {
+            info!("accepting https requests on {addr:?}");
+            listener.set_nonblocking(true)?;
+            let incoming = tls.incoming(tokio::net::TcpListener::from_std(listener)?);
+            tokio::spawn(
+                Server::builder(incoming)
+                    .executor(shutdown.connection_executor())
+                    .serve(make_service)
+                    .with_graceful_shutdown(stopped),
+            )
+        }
+        None => {
+            info!("accepting requests on {addr:?}");
+            tokio::spawn(
+                Server::from_tcp(listener)?
+                    .executor(shutdown.connection_executor())
+                    .serve(make_service)
+                    .with_graceful_shutdown(stopped),
+            )
+        }
+    };
+    tokio::spawn(reload_on_sighup(config, limits, tls));
+
+    let signal = wait_for_signal().await?;
+    info!(signal, "shutting down");
+
+    // give load balancers a chance to see we're no longer ready before draining
+    health.start_shutdown();
+    tokio::time::sleep(READINESS_GRACE_PERIOD).await;
+
+    let in_flight = {
+        let rabbit = rabbit.clone();
+        move || InFlight {
+            http_requests: metrics.requests_in_flight(),
+            rabbit_deliveries: rabbit.deliveries_in_flight(),
+        }
+    };
+    let drained = async { tokio::join!(&mut server_handle, &mut rabbit_consumer_handle) };
+
+    match shutdown.drain(drained, in_flight).await {
+        Some((server, consumer)) => {
+            server??;
+            consumer?;
+        }
+        None => {
+            // the abort token has already dropped the connections that were left, along
+            // with their handlers, this only stops the accept loop
+            server_handle.abort();
+            if timeout(ABORT_GRACE_PERIOD, shutdown.connections_closed())
+                .await
+                .is_err()
+            {
+                warn!("connections didn't close after being aborted");
+            }
+            if timeout(ABORT_GRACE_PERIOD, &mut rabbit_consumer_handle)
+                .await
+                .is_err()
+            {
+                warn!("rabbit consumer didn't stop after being aborted");
+            }
+        }
+    }
+
+    // last, as nothing that could still be using it is running any more
+    rabbit.close().await?;
 
     info!("goodbye!");
 
     Ok(())
 }
+
+// re-reads the config on a SIGHUP, so limits & the tls certificate can be changed
+// without a restart
+async fn reload_on_sighup(
+    mut current: ServerConfig,
+    limits: Limits,
+    tls: Option<Tls>,
+) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        match ServerConfig::load() {
+            Ok(config) => {
+                if current.needs_restart(&config) {
+                    warn!("only the limits & tls certificate have been reloaded, other changes need a restart");
+                }
+                limits.apply(&config.limits);
+                info!(limits = ?config.limits, "reloaded limits");
+                current = config;
+            }
+            Err(err) => warn!(%err, "failed to reload config, keeping the current limits"),
+        }
+        if let Some(Err(err)) = tls.as_ref().map(Tls::reload) {
+            warn!(%err, "failed to reload tls certificate, keeping the current one");
+        }
+    }
+    Ok(())
+}
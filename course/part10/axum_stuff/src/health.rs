@@ -0,0 +1,155 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use rabbit_stuff::rabbit::Rabbit;
+use serde::Serialize;
+use tokio::{sync::Semaphore, task::AbortHandle};
+use tracing::info;
+
+// what the health checks need to know about rabbit, so they can be checked without
+// a broker
+pub trait RabbitStatus: Send + Sync + 'static {
+    fn is_connected(&self) -> bool;
+    fn state(&self) -> String;
+}
+
+impl RabbitStatus for Rabbit {
+    fn is_connected(&self) -> bool {
+        Rabbit::is_connected(self)
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.connection_state())
+    }
+}
+
+// everything the health checks look at, clones share the same state
+#[derive(Clone)]
+pub struct Health {
+    rabbit: Arc<dyn RabbitStatus>,
+    consumer: AbortHandle,
+    concurrency_limit: Arc<Semaphore>,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl Health {
+    pub fn new(
+        rabbit: Arc<dyn RabbitStatus>,
+        consumer: AbortHandle,
+        concurrency_limit: Arc<Semaphore>,
+    ) -> Self {
+        Self {
+            rabbit,
+            consumer,
+            concurrency_limit,
+            shutting_down: Default::default(),
+        }
+    }
+
+    // fails readiness so load balancers stop sending new requests before we drain
+    pub fn start_shutdown(&self) {
+        info!("shutting down, readiness checks will now fail");
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let available_permits = self.concurrency_limit.available_permits();
+        HealthReport {
+            rabbit: RabbitHealth {
+                connected: self.rabbit.is_connected(),
+                state: self.rabbit.state(),
+            },
+            consumer: ConsumerHealth {
+                running: !self.consumer.is_finished(),
+            },
+            load_shed: LoadShedHealth {
+                // the load shed layer rejects requests once every permit is taken
+                shedding: available_permits == 0,
+                available_permits,
+            },
+            shutting_down: self.shutting_down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub rabbit: RabbitHealth,
+    pub consumer: ConsumerHealth,
+    pub load_shed: LoadShedHealth,
+    pub shutting_down: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RabbitHealth {
+    pub connected: bool,
+    pub state: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsumerHealth {
+    pub running: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadShedHealth {
+    pub shedding: bool,
+    pub available_permits: usize,
+}
+
+impl HealthReport {
+    // whether the process is working, restarting it is the only way to get the
+    // consumer back if its task has died
+    pub fn is_live(&self) -> bool {
+        self.consumer.running
+    }
+
+    // whether we should be sent new requests
+    pub fn is_ready(&self) -> bool {
+        self.is_live() && self.rabbit.connected && !self.load_shed.shedding && !self.shutting_down
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn healthy() -> HealthReport {
+        HealthReport {
+            rabbit: RabbitHealth {
+                connected: true,
+                state: "Connected".into(),
+            },
+            consumer: ConsumerHealth { running: true },
+            load_shed: LoadShedHealth {
+                shedding: false,
+                available_permits: 10,
+            },
+            shutting_down: false,
+        }
+    }
+
+    #[test]
+    fn only_a_dead_consumer_fails_liveness() {
+        let mut report = healthy();
+        assert!(report.is_live() && report.is_ready());
+
+        report.rabbit.connected = false;
+        report.load_shed.shedding = true;
+        report.shutting_down = true;
+        assert!(report.is_live());
+        assert!(!report.is_ready());
+
+        report.consumer.running = false;
+        assert!(!report.is_live());
+    }
+
+    #[test]
+    fn shutting_down_fails_readiness() {
+        let mut report = healthy();
+        report.shutting_down = true;
+        assert!(!report.is_ready());
+    }
+}
@@ -1,3 +1,10 @@
+pub mod api_error;
+pub mod health;
+pub mod metrics;
 pub mod routers;
+pub mod server_config;
+pub mod shutdown;
+pub mod tls;
 pub mod tower_stuff;
 pub mod tracing_config;
+pub mod validation;
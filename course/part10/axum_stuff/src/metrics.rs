@@ -0,0 +1,283 @@
+use std::{fmt, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, State},
+    middleware::Next,
+    response::Response,
+};
+use http::Request;
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet, EncodeMetric, MetricEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+        MetricType,
+    },
+    registry::Registry,
+};
+
+use crate::tower_stuff::{ConnectionLimitMetrics, PanicCaptureLayer};
+
+// what /metrics responds with
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    method: String,
+    route: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PublishLabels {
+    message_type: String,
+    outcome: &'static str,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+// an in-process prometheus registry, clones share the same metrics
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    requests: Family<RequestLabels, Counter>,
+    latency: HistogramFamily<RouteLabels>,
+    in_flight: Gauge,
+    load_shed_rejections: Counter,
+    rabbit_publishes: Family<PublishLabels, Counter>,
+    connection_permits: Gauge,
+    queued_connections: Gauge,
+    connection_limit: ConnectionLimitMetrics,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new(connection_limit: ConnectionLimitMetrics, panic_capture: PanicCaptureLayer) -> Self {
+        let metrics = Self {
+            registry: Default::default(),
+            requests: Default::default(),
+            // 5ms up to ~10s
+            latency: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.005, 2.0, 12))
+            }),
+            in_flight: Default::default(),
+            load_shed_rejections: Default::default(),
+            rabbit_publishes: Default::default(),
+            connection_permits: Default::default(),
+            queued_connections: Default::default(),
+            connection_limit,
+        };
+
+        let mut registry = Registry::with_prefix("axum_stuff");
+        registry.register(
+            "http_requests",
+            "Requests handled, by route & status",
+            metrics.requests.clone(),
+        );
+        registry.register(
+            "http_request_duration_seconds",
+            "How long requests took to handle, by route",
+            metrics.latency.clone(),
+        );
+        registry.register(
+            "http_requests_in_flight",
+            "Requests currently being handled",
+            metrics.in_flight.clone(),
+        );
+        registry.register(
+            "load_shed_rejections",
+            "Requests rejected as the server was overloaded",
+            metrics.load_shed_rejections.clone(),
+        );
+        registry.register(
+            "rabbit_publishes",
+            "Rabbit messages published, by message type & outcome",
+            metrics.rabbit_publishes.clone(),
+        );
+        registry.register(
+            "connection_permits_available",
+            "Connections that can be accepted before hitting the connection limit",
+            metrics.connection_permits.clone(),
+        );
+        registry.register(
+            "connections_queued",
+            "Connections waiting for a permit whilst at the connection limit",
+            metrics.queued_connections.clone(),
+        );
+        registry.register(
+            "request_handler_panics",
+            "Request handlers that panicked & were answered with a 500",
+            PanicCount(panic_capture),
+        );
+
+        Self {
+            registry: Arc::new(registry),
+            ..metrics
+        }
+    }
+
+    // requests that have started being handled but haven't responded yet
+    pub fn requests_in_flight(&self) -> i64 {
+        self.in_flight.get()
+    }
+
+    pub fn record_load_shed(&self) {
+        self.load_shed_rejections.inc();
+    }
+
+    pub fn record_publish<T, E>(&self, message_type: &str, result: &Result<T, E>) {
+        let outcome = match result {
+            Ok(_) => "ok",
+            Err(_) => "error",
+        };
+        self.rabbit_publishes
+            .get_or_create(&PublishLabels {
+                message_type: message_type.to_string(),
+                outcome,
+            })
+            .inc();
+    }
+
+    // the prometheus text format of every metric
+    pub fn encode(&self) -> Result<String, fmt::Error> {
+        // gauges that are owned by something else are read at scrape time
+        self.connection_permits
+            .set(self.connection_limit.available_permits() as i64);
+        self.queued_connections
+            .set(self.connection_limit.queued_connections() as i64);
+
+        let mut body = String::new();
+        encode(&mut body, &self.registry)?;
+        Ok(body)
+    }
+}
+
+// the panic capture layer keeps its own count, which is read at scrape time
+#[derive(Debug)]
+struct PanicCount(PanicCaptureLayer);
+
+impl EncodeMetric for PanicCount {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), fmt::Error> {
+        encoder.encode_counter::<(), _, u64>(&self.0.panic_count(), None)
+    }
+
+    fn metric_type(&self) -> MetricType {
+        MetricType::Counter
+    }
+}
+
+// decrements the in flight gauge even if the request is cancelled
+struct InFlight(Gauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// middleware recording request counts & latencies, keyed by the route that matched
+// rather than the path so that path params don't explode the number of series
+pub async fn track_requests<B>(
+    State(metrics): State<Metrics>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+
+    metrics.in_flight.inc();
+    let _in_flight = InFlight(metrics.in_flight.clone());
+    let response = next.run(request).await;
+
+    metrics
+        .latency
+        .get_or_create(&RouteLabels {
+            method: method.clone(),
+            route: route.clone(),
+        })
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .requests
+        .get_or_create(&RequestLabels {
+            method,
+            route,
+            status: response.status().as_u16(),
+        })
+        .inc();
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::tower_stuff::ConnectionLimitLayer;
+
+    #[tokio::test]
+    async fn requests_are_recorded_by_matched_path() {
+        let metrics = Metrics::new(
+            ConnectionLimitLayer::new(3).metrics(),
+            PanicCaptureLayer::default(),
+        );
+        let router: Router = Router::new()
+            .route("/numbers/:number", get(|| async { "number" }))
+            .layer(axum::middleware::from_fn_with_state(
+                metrics.clone(),
+                track_requests,
+            ));
+
+        for number in 1..=2 {
+            let req = Request::get(format!("/numbers/{number}"))
+                .body(hyper::Body::empty())
+                .unwrap();
+            router.clone().oneshot(req).await.unwrap();
+        }
+        metrics.record_publish::<(), ()>("msg-joseph", &Err(()));
+
+        let body = metrics.encode().unwrap();
+        assert!(
+            body.contains(r#"axum_stuff_http_requests_total{method="GET",route="/numbers/:number",status="200"} 2"#),
+            "{body}"
+        );
+        assert!(
+            body.contains(
+                r#"axum_stuff_rabbit_publishes_total{message_type="msg-joseph",outcome="error"} 1"#
+            ),
+            "{body}"
+        );
+        assert!(
+            body.contains("axum_stuff_connection_permits_available 3"),
+            "{body}"
+        );
+        assert!(body.contains("axum_stuff_connections_queued 0"), "{body}");
+        assert!(
+            body.contains("axum_stuff_request_handler_panics_total 0"),
+            "{body}"
+        );
+        assert!(
+            body.contains("axum_stuff_http_requests_in_flight 0"),
+            "{body}"
+        );
+    }
+}
@@ -1,4 +1,9 @@
+mod messages;
+mod openapi;
+
 use std::{
+    io,
+    net::SocketAddr,
     sync::{
         atomic::{AtomicBool, AtomicI16, Ordering},
         Arc,
@@ -7,23 +12,43 @@ use std::{
 };
 
 use axum::{
-    body::HttpBody,
+    body::{Bytes, HttpBody, StreamBody},
     error_handling::HandleErrorLayer,
-    extract::{MatchedPath, Path, State},
+    extract::{
+        connect_info::IntoMakeServiceWithConnectInfo,
+        BodyStream,
+        DefaultBodyLimit,
+        MatchedPath,
+        State,
+    },
     handler::Handler,
-    http::{Request, StatusCode},
+    http::{
+        header::{HeaderName, ACCEPT_ENCODING, ALLOW, CONTENT_TYPE, LOCATION, RETRY_AFTER},
+        Request,
+        StatusCode,
+    },
     middleware::Next,
     response::{IntoResponse, Response},
-    routing::{get, IntoMakeService},
-    Json,
+    routing::{get, post},
+    Extension,
     Router,
 };
 use axum_extra::routing::{RouterExt, TypedPath};
+use bytes::BytesMut;
+use futures::{StreamExt, TryStreamExt};
 use rabbit_stuff::{
+    feed::ProcessedFeed,
     impls::{MyMessage, OtherMessage, Pupil, SchoolAge},
-    rabbit::{PublishError, Rabbit, EXCHANGE, MESSAGE_TYPE, MESSAGE_TYPE_2},
+    jobs::{JobId, JobMessage, JobStatus, JobStore, JobStoreError},
+    rabbit::{PublishError, Rabbit, EXCHANGE, JOB_MESSAGE_TYPE, MESSAGE_TYPE, MESSAGE_TYPE_2},
 };
 use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio_util::{
+    codec::{Decoder, FramedRead, LinesCodec, LinesCodecError},
+    io::StreamReader,
+    sync::CancellationToken,
+};
 use tower::{
     limit::GlobalConcurrencyLimitLayer,
     load_shed::LoadShedLayer,
@@ -32,18 +57,91 @@ use tower::{
 };
 use tower_http::compression::{CompressionLayer, DefaultPredicate};
 use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    api_error::{ApiError, ApiErrorBody, ErrorCode, Json, Path},
+    health::{Health, HealthReport},
+    metrics::{self, track_requests, Metrics},
+    server_config::ServerConfig,
+    tower_stuff::{
+        ClientRateLimitLayer,
+        FaultInjectionLayer,
+        FaultRule,
+        FaultRules,
+        PanicCaptureLayer,
+        RequestId,
+        RequestIdLayer,
+        ResponseCacheLayer,
+    },
+    validation::{validate, Valid, Validate, Validator},
+};
 
-use crate::tower_stuff::PanicCaptureLayer;
+// seconds a load shed client is told to wait, roughly how long /hello takes to respond
+const LOAD_SHED_RETRY_AFTER_SECS: &str = "1";
 
-pub fn service(rabbit: Rabbit) -> IntoMakeService<Router> {
-    Router::new()
+// the largest body the numbers routes accept, apart from /numbers/batch which is
+// limited per line instead
+const NUMBERS_BODY_LIMIT: usize = 1024;
+const BATCH_LINE_LIMIT: usize = 1024;
+// the largest numerator or denominator, so dividing can't overflow
+const NUMBER_LIMIT: isize = 1_000_000_000;
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// how long the responses of pure GET routes are cached for & how much memory they use
+const RESPONSE_CACHE_TTL: Duration = Duration::from_secs(60);
+const RESPONSE_CACHE_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+// the handles the routes share with the rest of the server, built once at startup
+pub struct ServiceDeps {
+    pub rabbit: Arc<Rabbit>,
+    // shared across all connections & can be resized at runtime
+    pub concurrency_limit: Arc<Semaphore>,
+    pub health: Health,
+    pub metrics: Metrics,
+    pub jobs: Arc<dyn JobStore>,
+    pub feed: ProcessedFeed,
+    pub rate_limit: ClientRateLimitLayer,
+    pub panic_capture: PanicCaptureLayer,
+    // cancelled when shutdown starts
+    pub stop: CancellationToken,
+}
+
+// clients' ips are kept as ConnectInfo for the rate limit. fault injection should only
+// be turned on for local testing, as anyone can use it
+pub fn service(
+    deps: ServiceDeps,
+    config: &ServerConfig,
+) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr> {
+    let ServiceDeps {
+        rabbit,
+        concurrency_limit,
+        health,
+        metrics,
+        jobs,
+        feed,
+        rate_limit,
+        panic_capture,
+        stop,
+    } = deps;
+    let publisher = Publisher {
+        rabbit,
+        metrics: metrics.clone(),
+    };
+
+    let router = Router::new()
         .route("/endpoint", get(endpoint))
-        .with_state(Arc::new(rabbit))
-        // curl localhost:25565/hello
-        .route(
-            "/hello",
-            get(hello).post(world), // .layer(EveryOtherRequestLayer::default()),
+        .with_state(publisher.clone())
+        .nest(
+            "/jobs",
+            jobs_subrouter(Jobs {
+                publisher,
+                store: jobs,
+            }),
         )
+        // curl localhost:25565/hello
+        .route("/hello", get(hello).post(world))
         // curl localhost:25565/world
         .route(
             "/world",
@@ -52,50 +150,167 @@ pub fn service(rabbit: Rabbit) -> IntoMakeService<Router> {
                 flip_flop,
             )),
         )
-        .nest("/:a", a_path_subrouter())
-        .nest("/numbers", numbers_subrouter())
+        .merge(documented_routes(panic_capture));
+    let router = if config.fault_injection {
+        let faults = FaultRules::default();
+        router
+            // curl localhost:25565/hello -H 'x-fault: 503'
+            .layer(FaultInjectionLayer::new(faults.clone()).allow_header())
+            // registered after the fault layer so faults can always be turned off again
+            .nest("/admin", admin_subrouter(faults))
+    } else {
+        router
+    };
+
+    router
         .layer(
             ServiceBuilder::new()
-                .layer(HandleErrorLayer::new(|err: BoxError| async move {
-                    (StatusCode::SERVICE_UNAVAILABLE, err.to_string())
+                .layer(HandleErrorLayer::new({
+                    let metrics = metrics.clone();
+                    move |err: BoxError| {
+                        metrics.record_load_shed();
+                        async move {
+                            // let clients know when it's worth trying again
+                            (
+                                [(RETRY_AFTER, LOAD_SHED_RETRY_AFTER_SECS)],
+                                ApiError::new(
+                                    StatusCode::SERVICE_UNAVAILABLE,
+                                    ErrorCode::Overloaded,
+                                    err.to_string(),
+                                ),
+                            )
+                        }
+                    }
                 }))
                 .layer(LoadShedLayer::new())
-                .layer(GlobalConcurrencyLimitLayer::new(54321)), // across all connections
+                .layer(GlobalConcurrencyLimitLayer::with_semaphore(
+                    concurrency_limit,
+                )),
         )
+        // before the concurrency limit, so rate limited clients don't take its permits
+        .layer(rate_limit)
+        .layer(axum::middleware::from_fn_with_state(
+            metrics.clone(),
+            track_requests,
+        ))
+        // outside of the load shed layer so probes are still answered when overloaded
+        .merge(health_subrouter(health))
+        // long lived streams, which would otherwise hold a concurrency permit forever
+        .nest("/messages", messages::router(feed, stop))
+        // curl localhost:25565/metrics
+        .route("/metrics", get(metrics_handler).with_state(metrics))
+        // curl localhost:25565/openapi.json
+        // open localhost:25565/docs, which needs access to unpkg.com for swagger ui
+        .merge(openapi::router())
+        .fallback(not_found)
+        .layer(axum::middleware::map_response(method_not_allowed))
+        // outermost so every response, including rejections, carries the request id
+        .layer(RequestIdLayer)
         .with_state(Arc::new(AtomicI16::default()))
-        .into_make_service()
+        .into_make_service_with_connect_info::<SocketAddr>()
 }
 
-fn numbers_subrouter<S, B>() -> Router<S, B>
+// the routes described by the openapi spec, see openapi::ApiDoc
+fn documented_routes<S, B>(panic_capture: PanicCaptureLayer) -> Router<S, B>
 where
     S: Clone + Send + Sync + 'static,
     B: HttpBody + Send + Sync + 'static,
-    <B as HttpBody>::Data: Send,
-    <B as HttpBody>::Error: std::error::Error + Send + Sync,
+    <B as HttpBody>::Data: Into<Bytes> + Send,
+    <B as HttpBody>::Error: std::error::Error + Send + Sync + 'static,
+{
+    // for the routes whose responses only depend on their path
+    let cache = || ResponseCacheLayer::new(RESPONSE_CACHE_TTL, RESPONSE_CACHE_MAX_BYTES / 2);
+
+    Router::new()
+        .nest("/:a", a_path_subrouter(cache()))
+        .nest("/numbers", numbers_subrouter(cache(), panic_capture))
+}
+
+fn numbers_subrouter<S, B>(
+    cache: ResponseCacheLayer,
+    panic_capture: PanicCaptureLayer,
+) -> Router<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + Sync + 'static,
+    <B as HttpBody>::Data: Into<Bytes> + Send,
+    <B as HttpBody>::Error: std::error::Error + Send + Sync + 'static,
 {
     Router::new()
         // curl localhost:25565/numbers/1
-        .route("/1", get(|| async { "one" }))
+        .route("/1", get(one))
         // curl localhost:25565/numbers/2
-        .route("/2", get(|| async { (StatusCode::CREATED, "two") }))
+        .route("/2", get(two))
         // curl localhost:25565/numbers/5
-        .typed_get(|NumbersPath { number }: NumbersPath| async move {
-            format!("dynamic number: {number}")
-        })
+        .typed_get(dynamic_number)
+        // only the routes above, divide depends on the body
+        .layer(cache)
         // curl -v localhost:25565/numbers/divide -X GET --json '{"numerator": 13, "denominator": 5}'
-        .route("/divide", get(divide.layer(PanicCaptureLayer)))
+        .route("/divide", get(divide.layer(panic_capture)))
         // curl -v localhost:25565/numbers/divide2 -X GET --json '{"numerator": 13, "denominator": 5}'
         .route("/divide2", get(divide2))
-    // .layer(EveryOtherRequestLayer::default())
+        .layer(DefaultBodyLimit::max(NUMBERS_BODY_LIMIT))
+        // streamed, so each line is limited instead of the whole body
+        // printf '{"numerator": 13, "denominator": 5}\n{"numerator": 1, "denominator": 0}\n' | curl -N localhost:25565/numbers/batch -H 'content-type: application/x-ndjson' --data-binary @-
+        .route("/batch", post(batch))
+}
+
+fn health_subrouter<S, B>(health: Health) -> Router<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + Sync + 'static,
+    <B as HttpBody>::Data: Send,
+    <B as HttpBody>::Error: std::error::Error + Send + Sync,
+{
+    Router::new()
+        // curl localhost:25565/healthz
+        .route("/healthz", get(healthz))
+        // curl localhost:25565/readyz
+        .route("/readyz", get(readyz))
+        .with_state(health)
 }
 
-#[derive(Debug, TypedPath, Deserialize)]
-#[typed_path("/:number")]
+fn jobs_subrouter<S, B>(jobs: Jobs) -> Router<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + Sync + 'static,
+    <B as HttpBody>::Data: Send,
+    <B as HttpBody>::Error: std::error::Error + Send + Sync,
+{
+    Router::new()
+        // curl -v localhost:25565/jobs --json '{"school_age": "Primary", "pupils": [{"first_name": "jason", "second_name": "mccullough"}]}'
+        .route("/", post(create_job))
+        // curl localhost:25565/jobs/5fa5b0a6-4a4c-4b4e-9d5e-0c1f1c1d2e3f
+        .route("/:id", get(get_job))
+        .with_state(jobs)
+}
+
+fn admin_subrouter<S, B>(faults: FaultRules) -> Router<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + Sync + 'static,
+    <B as HttpBody>::Data: Send,
+    <B as HttpBody>::Error: std::error::Error + Send + Sync,
+{
+    Router::new()
+        // curl localhost:25565/admin/faults
+        // curl localhost:25565/admin/faults -X PUT --json '[{"fault": {"status": 503}, "route": "/numbers/*", "percentage": 50}]'
+        // curl localhost:25565/admin/faults -X DELETE
+        .route(
+            "/faults",
+            get(get_faults).put(put_faults).delete(delete_faults),
+        )
+        .with_state(faults)
+}
+
+#[derive(Debug, TypedPath, Deserialize, IntoParams)]
+#[typed_path("/:number", rejection(ApiError))]
+#[into_params(parameter_in = Path)]
 struct NumbersPath {
     number: usize,
 }
 
-fn a_path_subrouter<S, B>() -> Router<S, B>
+fn a_path_subrouter<S, B>(cache: ResponseCacheLayer) -> Router<S, B>
 where
     S: Clone + Send + Sync + 'static,
     B: HttpBody + Send + Sync + 'static,
@@ -104,20 +319,59 @@ where
 {
     Router::new()
         // curl -v localhost:25565/swap/please
-        .route(
-            "/:b",
-            get(
-                |Path((a, b)): Path<(String, String)>, matched_path: MatchedPath| async move {
-                    info!(matched_path=?matched_path, "hit the long url endpoint");
-                    format!(
-                        "if this url was 100 times longer and reversed: /{b}/{a}",
-                        b = b.repeat(100),
-                        a = a.repeat(100)
-                    )
-                },
-            ),
-        )
+        .route("/:b", get(long_url))
         .layer(CompressionLayer::<DefaultPredicate>::default())
+        // caches the compressed response, so saves compressing it every time too
+        .layer(cache.vary_by(ACCEPT_ENCODING))
+}
+
+#[utoipa::path(
+    get,
+    path = "/numbers/1",
+    responses((status = 200, description = "the number one", body = String)),
+)]
+async fn one() -> &'static str {
+    "one"
+}
+
+#[utoipa::path(
+    get,
+    path = "/numbers/2",
+    responses((status = 201, description = "the number two", body = String)),
+)]
+async fn two() -> (StatusCode, &'static str) {
+    (StatusCode::CREATED, "two")
+}
+
+#[utoipa::path(
+    get,
+    path = "/numbers/{number}",
+    params(NumbersPath),
+    responses(
+        (status = 200, description = "the number that was asked for", body = String),
+        (status = 400, description = "number isn't a positive integer", body = ApiErrorEnvelope),
+    ),
+)]
+async fn dynamic_number(NumbersPath { number }: NumbersPath) -> String {
+    format!("dynamic number: {number}")
+}
+
+#[utoipa::path(
+    get,
+    path = "/{a}/{b}",
+    params(
+        ("a" = String, Path, description = "repeated 100 times & moved to the end"),
+        ("b" = String, Path, description = "repeated 100 times & moved to the start"),
+    ),
+    responses((status = 200, description = "the path reversed, made 100 times longer", body = String)),
+)]
+async fn long_url(Path((a, b)): Path<(String, String)>, matched_path: MatchedPath) -> String {
+    info!(matched_path=?matched_path, "hit the long url endpoint");
+    format!(
+        "if this url was 100 times longer and reversed: /{b}/{a}",
+        b = b.repeat(100),
+        a = a.repeat(100)
+    )
 }
 
 async fn hello(State(counter): State<Arc<AtomicI16>>) -> Response {
@@ -127,23 +381,44 @@ async fn hello(State(counter): State<Arc<AtomicI16>>) -> Response {
     "hello world".into_response()
 }
 
-#[derive(Debug, thiserror::Error)]
-enum EndpointError {
-    #[error("failed to publish rabbit msg: {0}")]
-    Publish(#[from] PublishError),
+impl From<PublishError> for ApiError {
+    fn from(err: PublishError) -> Self {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::PublishFailed,
+            format!("failed to publish rabbit msg: {err}"),
+        )
+    }
 }
 
-impl IntoResponse for EndpointError {
-    fn into_response(self) -> Response {
-        match self {
-            EndpointError::Publish(err) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
-            }
-        }
+// publishes through rabbit, recording the outcome of every publish
+#[derive(Clone)]
+struct Publisher {
+    rabbit: Arc<Rabbit>,
+    metrics: Metrics,
+}
+
+impl Publisher {
+    async fn publish_json<S: Serialize>(
+        &self,
+        exchange: &str,
+        message_type: &str,
+        body: S,
+        request_id: &RequestId,
+    ) -> Result<(), PublishError> {
+        let result = self
+            .rabbit
+            .publish_json_with_request_id(exchange, message_type, body, Some(request_id.as_str()))
+            .await;
+        self.metrics.record_publish(message_type, &result);
+        result.map(|_| ())
     }
 }
 
-async fn endpoint(State(rabbit): State<Arc<Rabbit>>) -> Result<(), EndpointError> {
+async fn endpoint(
+    State(rabbit): State<Publisher>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<(), ApiError> {
     tokio::time::sleep(Duration::from_millis(500)).await;
 
     rabbit
@@ -154,6 +429,7 @@ async fn endpoint(State(rabbit): State<Arc<Rabbit>>) -> Result<(), EndpointError
                 age: 25,
                 name: "joseph".into(),
             },
+            &request_id,
         )
         .await?;
 
@@ -167,6 +443,7 @@ async fn endpoint(State(rabbit): State<Arc<Rabbit>>) -> Result<(), EndpointError
                 age: 25,
                 name: "\newline encoded".into(),
             },
+            &request_id,
         )
         .await?;
 
@@ -189,6 +466,7 @@ async fn endpoint(State(rabbit): State<Arc<Rabbit>>) -> Result<(), EndpointError
                     },
                 ],
             },
+            &request_id,
         )
         .await?;
 
@@ -197,6 +475,77 @@ async fn endpoint(State(rabbit): State<Arc<Rabbit>>) -> Result<(), EndpointError
     Ok(())
 }
 
+#[derive(Clone)]
+struct Jobs {
+    publisher: Publisher,
+    store: Arc<dyn JobStore>,
+}
+
+#[derive(Debug, Serialize)]
+struct JobResponse {
+    id: JobId,
+    #[serde(flatten)]
+    status: JobStatus,
+}
+
+impl From<JobStoreError> for ApiError {
+    fn from(err: JobStoreError) -> Self {
+        let (status, code) = match err {
+            JobStoreError::NotFound(_) => (StatusCode::NOT_FOUND, ErrorCode::NotFound),
+            JobStoreError::Other(_) => (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal),
+        };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+// queues the job & responds straight away, the job's status can be polled at the
+// url in the location header
+async fn create_job(
+    State(jobs): State<Jobs>,
+    Extension(request_id): Extension<RequestId>,
+    Json(message): Json<OtherMessage>,
+) -> Result<(StatusCode, [(HeaderName, String); 1], Json<JobResponse>), ApiError> {
+    let id = Uuid::new_v4();
+    jobs.store.create(id).await?;
+
+    let published = jobs
+        .publisher
+        .publish_json(
+            EXCHANGE,
+            JOB_MESSAGE_TYPE,
+            JobMessage { id, message },
+            &request_id,
+        )
+        .await;
+    if let Err(err) = published {
+        // nothing is ever going to pick the job up
+        let error = err.to_string();
+        jobs.store
+            .set_status(id, JobStatus::Failed { error })
+            .await?;
+        return Err(err.into());
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [(LOCATION, format!("/jobs/{id}"))],
+        Json(JobResponse {
+            id,
+            status: JobStatus::Queued,
+        }),
+    ))
+}
+
+async fn get_job(
+    State(jobs): State<Jobs>,
+    Path(id): Path<JobId>,
+) -> Result<Json<JobResponse>, ApiError> {
+    match jobs.store.status(id).await? {
+        Some(status) => Ok(Json(JobResponse { id, status })),
+        None => Err(JobStoreError::NotFound(id).into()),
+    }
+}
+
 async fn world() -> impl IntoResponse {
     (StatusCode::ACCEPTED, "HELLO WORLD")
 }
@@ -207,28 +556,53 @@ async fn flip_flop<B>(
     next: Next<B>,
 ) -> Response {
     if flipper.fetch_xor(true, Ordering::Relaxed) {
-        StatusCode::FORBIDDEN.into_response()
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::Forbidden,
+            "every other request is forbidden",
+        )
+        .into_response()
     } else {
         next.run(request).await
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, ToSchema)]
 struct Numbers {
     numerator: isize,
     denominator: isize,
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, ToSchema)]
 struct DivideResult {
     result: isize,
 }
 
+impl Validate for Numbers {
+    fn validate(&self, v: &mut Validator) {
+        let range = -NUMBER_LIMIT..=NUMBER_LIMIT;
+        v.range("numerator", self.numerator, range.clone())
+            .range("denominator", self.denominator, range)
+            .not_zero("denominator", self.denominator);
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/numbers/divide",
+    request_body = Numbers,
+    responses(
+        (status = 200, description = "numerator / denominator", body = DivideResult),
+        (status = 400, description = "the body isn't valid json", body = ApiErrorEnvelope),
+        (status = 413, description = "the body is too large", body = ApiErrorEnvelope),
+        (status = 422, description = "a number is zero or out of range", body = ApiErrorEnvelope),
+    ),
+)]
 async fn divide(
-    Json(Numbers {
+    Valid(Numbers {
         numerator,
         denominator,
-    }): Json<Numbers>,
+    }): Valid<Numbers>,
 ) -> Json<DivideResult> {
     Json(DivideResult {
         result: numerator / denominator,
@@ -239,18 +613,33 @@ async fn divide(
 #[error("denominator cannot be zero")]
 struct DivideByZeroError;
 
-impl IntoResponse for DivideByZeroError {
-    fn into_response(self) -> Response {
-        (StatusCode::BAD_REQUEST, "numerator cannot be zero").into_response()
+impl From<DivideByZeroError> for ApiError {
+    fn from(err: DivideByZeroError) -> Self {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::DivideByZero,
+            err.to_string(),
+        )
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/numbers/divide2",
+    request_body = Numbers,
+    responses(
+        (status = 200, description = "numerator / denominator", body = DivideResult),
+        (status = 400, description = "the body isn't valid json", body = ApiErrorEnvelope),
+        (status = 413, description = "the body is too large", body = ApiErrorEnvelope),
+        (status = 422, description = "a number is zero or out of range", body = ApiErrorEnvelope),
+    ),
+)]
 async fn divide2(
-    Json(Numbers {
+    Valid(Numbers {
         numerator,
         denominator,
-    }): Json<Numbers>,
-) -> Result<Json<DivideResult>, DivideByZeroError> {
+    }): Valid<Numbers>,
+) -> Result<Json<DivideResult>, ApiError> {
     let res = numerator
         .checked_div(denominator)
         .map(|result| Json(DivideResult { result }))
@@ -258,3 +647,341 @@ async fn divide2(
 
     Ok(res)
 }
+
+// one line of a /numbers/batch response, for the line of the request with the same number
+#[derive(Debug, Serialize, ToSchema)]
+struct BatchLine {
+    line: usize,
+    #[serde(flatten)]
+    outcome: BatchOutcome,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum BatchOutcome {
+    Result(isize),
+    Error(ApiErrorBody),
+}
+
+impl BatchLine {
+    fn divide(line: usize, json: &str) -> Self {
+        let outcome = serde_json::from_str::<Numbers>(json)
+            .map_err(|err| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    ErrorCode::InvalidJson,
+                    err.to_string(),
+                )
+            })
+            .and_then(|numbers| {
+                validate(&numbers)?;
+                Ok(numbers.numerator / numbers.denominator)
+            });
+        let outcome = match outcome {
+            Ok(result) => BatchOutcome::Result(result),
+            Err(err) => BatchOutcome::Error(err.into_body()),
+        };
+        Self { line, outcome }
+    }
+}
+
+// a line of a /numbers/batch request
+enum BatchInput {
+    Line(String),
+    TooLong,
+}
+
+// LinesCodec, except a line that's too long is an item rather than an error, as
+// FramedRead stops at the first error. LinesCodec discards the rest of the line itself
+struct BatchLines(LinesCodec);
+
+impl BatchLines {
+    fn item(
+        decoded: Result<Option<String>, LinesCodecError>,
+    ) -> Result<Option<BatchInput>, LinesCodecError> {
+        match decoded {
+            Ok(line) => Ok(line.map(BatchInput::Line)),
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(BatchInput::TooLong)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Decoder for BatchLines {
+    type Item = BatchInput;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Self::item(self.0.decode(buf))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Self::item(self.0.decode_eof(buf))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/numbers/batch",
+    request_body(
+        content = String,
+        content_type = "application/x-ndjson",
+        description = "a Numbers object on each line",
+    ),
+    responses(
+        (status = 200, description = "a BatchLine for each line of the request, sent as soon as it's read. a line that's too long gets a payload_too_large error & the batch carries on with the next line", body = BatchLine, content_type = "application/x-ndjson"),
+    ),
+)]
+async fn batch(body: BodyStream) -> impl IntoResponse {
+    let body = StreamReader::new(body.map_err(io::Error::other));
+    let lines = FramedRead::new(
+        body,
+        BatchLines(LinesCodec::new_with_max_length(BATCH_LINE_LIMIT)),
+    );
+
+    let results = lines.enumerate().filter_map(|(i, line)| async move {
+        let line_number = i + 1;
+        let result = match line {
+            Ok(BatchInput::Line(line)) if line.trim().is_empty() => return None,
+            Ok(BatchInput::Line(line)) => BatchLine::divide(line_number, &line),
+            Ok(BatchInput::TooLong) => BatchLine {
+                line: line_number,
+                outcome: BatchOutcome::Error(
+                    ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        ErrorCode::PayloadTooLarge,
+                        format!("lines can't be longer than {BATCH_LINE_LIMIT} bytes"),
+                    )
+                    .into_body(),
+                ),
+            },
+            // the request body failed, so the response is too
+            Err(LinesCodecError::Io(err)) => return Some(Err(err)),
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                unreachable!("BatchLines turns long lines into items")
+            }
+        };
+        let mut json = serde_json::to_vec(&result).unwrap();
+        json.push(b'\n');
+        Some(Ok(Bytes::from(json)))
+    });
+
+    (
+        [(CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        StreamBody::new(results),
+    )
+}
+
+async fn get_faults(State(faults): State<FaultRules>) -> Json<Vec<FaultRule>> {
+    Json(faults.get())
+}
+
+async fn put_faults(
+    State(faults): State<FaultRules>,
+    Json(rules): Json<Vec<FaultRule>>,
+) -> Json<Vec<FaultRule>> {
+    faults.set(rules);
+    Json(faults.get())
+}
+
+async fn delete_faults(State(faults): State<FaultRules>) -> StatusCode {
+    faults.set(Vec::new());
+    StatusCode::NO_CONTENT
+}
+
+fn health_response(healthy: bool, report: HealthReport) -> (StatusCode, Json<HealthReport>) {
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+async fn healthz(State(health): State<Health>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report();
+    health_response(report.is_live(), report)
+}
+
+async fn readyz(State(health): State<Health>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report();
+    health_response(report.is_ready(), report)
+}
+
+async fn metrics_handler(State(metrics): State<Metrics>) -> Result<Response, ApiError> {
+    let body = metrics.encode().map_err(|err| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Internal,
+            format!("failed to encode metrics: {err}"),
+        )
+    })?;
+    Ok(([(CONTENT_TYPE, metrics::CONTENT_TYPE)], body).into_response())
+}
+
+async fn not_found() -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NotFound,
+        "no route matched",
+    )
+}
+
+// the 405s axum responds with have an empty body, so they're given the usual one
+async fn method_not_allowed(res: Response) -> Response {
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED || res.headers().contains_key(CONTENT_TYPE) {
+        return res;
+    }
+    let mut error = ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        ErrorCode::MethodNotAllowed,
+        "method not allowed for this route",
+    )
+    .into_response();
+    if let Some(allow) = res.headers().get(ALLOW) {
+        error.headers_mut().insert(ALLOW, allow.clone());
+    }
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::health::RabbitStatus;
+
+    fn numbers() -> Router {
+        let cache = ResponseCacheLayer::new(RESPONSE_CACHE_TTL, RESPONSE_CACHE_MAX_BYTES);
+        Router::new().nest(
+            "/numbers",
+            numbers_subrouter(cache, PanicCaptureLayer::default()),
+        )
+    }
+
+    #[tokio::test]
+    async fn batch_streams_a_result_or_error_per_line() {
+        let long_line = format!("{{\"numerator\": {}}}", "1".repeat(BATCH_LINE_LIMIT));
+        let body = format!(
+            "{{\"numerator\": 13, \"denominator\": 5}}\n\n{{\"numerator\": 1, \"denominator\": 0}}\nnot json\n{long_line}\n{{\"numerator\": 9, \"denominator\": 3}}\n"
+        );
+        let req = Request::post("/numbers/batch")
+            .header(CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+            .body(Body::from(body))
+            .unwrap();
+
+        let res = numbers().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(NDJSON_CONTENT_TYPE, res.headers()[CONTENT_TYPE]);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let lines = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(5, lines.len());
+        assert_eq!(serde_json::json!({"line": 1, "result": 2}), lines[0]);
+        assert_eq!(3, lines[1]["line"]);
+        assert_eq!("validation_failed", lines[1]["error"]["code"]);
+        assert_eq!("denominator", lines[1]["error"]["details"][0]["field"]);
+        assert_eq!(4, lines[2]["line"]);
+        assert_eq!("invalid_json", lines[2]["error"]["code"]);
+        assert_eq!("payload_too_large", lines[3]["error"]["code"]);
+        // the long line doesn't end the batch
+        assert_eq!(serde_json::json!({"line": 6, "result": 3}), lines[4]);
+    }
+
+    #[tokio::test]
+    async fn nested_routes_are_cached_by_their_full_path() {
+        let router = Router::new().merge(documented_routes(PanicCaptureLayer::default()));
+        let get = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let router = router.clone();
+            async move {
+                let res = router.oneshot(req).await.unwrap();
+                hyper::body::to_bytes(res.into_body()).await.unwrap()
+            }
+        };
+
+        let foo = get("/foo/bar").await;
+        assert!(foo.ends_with(b"foofoo"), "{foo:?}");
+        let baz = get("/baz/bar").await;
+        assert!(baz.ends_with(b"bazbaz"), "{baz:?}");
+        assert_eq!(foo, get("/foo/bar").await);
+    }
+
+    #[tokio::test]
+    async fn wrong_methods_get_an_api_error() {
+        let router = Router::new()
+            .route("/", get(|| async {}))
+            .layer(axum::middleware::map_response(method_not_allowed));
+        let req = Request::post("/").body(Body::empty()).unwrap();
+
+        let res = router.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+        assert_eq!("GET,HEAD", res.headers()[ALLOW]);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("method_not_allowed", body["error"]["code"]);
+    }
+
+    struct Connected;
+
+    impl RabbitStatus for Connected {
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        fn state(&self) -> String {
+            "Connected".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn only_readiness_fails_once_shutting_down() {
+        let consumer = tokio::spawn(std::future::pending::<()>());
+        let health = Health::new(
+            Arc::new(Connected),
+            consumer.abort_handle(),
+            Arc::new(Semaphore::new(1)),
+        );
+        let router: Router = health_subrouter(health.clone());
+        let status = |path: &'static str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let router = router.clone();
+            async move { router.oneshot(req).await.unwrap().status() }
+        };
+
+        assert_eq!(StatusCode::OK, status("/healthz").await);
+        assert_eq!(StatusCode::OK, status("/readyz").await);
+
+        health.start_shutdown();
+        assert_eq!(StatusCode::OK, status("/healthz").await);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status("/readyz").await);
+        consumer.abort();
+    }
+
+    #[tokio::test]
+    async fn divide_rejects_large_bodies_and_zero_denominators() {
+        let divide = |body: String| {
+            let req = Request::get("/numbers/divide")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            numbers().oneshot(req)
+        };
+
+        let padding = " ".repeat(NUMBERS_BODY_LIMIT);
+        let res = divide(format!(r#"{{"numerator": 1, "denominator": 1{padding}}}"#))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+
+        let res = divide(r#"{"numerator": 1, "denominator": 0}"#.to_string())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    }
+}
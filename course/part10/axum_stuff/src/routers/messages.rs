@@ -0,0 +1,229 @@
+use std::convert::Infallible;
+
+use axum::{
+    body::HttpBody,
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        State,
+        WebSocketUpgrade,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Response,
+    },
+    routing::get,
+    Router,
+};
+use futures::{stream, SinkExt, Stream, StreamExt};
+use rabbit_stuff::feed::{ProcessedFeed, ProcessedMessage};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::broadcast::{error::RecvError, Receiver},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::api_error::Query;
+
+// the feed along with the token cancelled when shutdown starts, as subscribers would
+// otherwise hold the drain open until it times out
+#[derive(Clone)]
+struct Feed {
+    feed: ProcessedFeed,
+    stop: CancellationToken,
+}
+
+pub(super) fn router<S, B>(feed: ProcessedFeed, stop: CancellationToken) -> Router<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    Router::new()
+        // curl -N localhost:25565/messages/sse?message_type=msg-joseph
+        .route("/sse", get(sse))
+        // websocat ws://localhost:25565/messages/ws?message_type=msg-joseph-2
+        .route("/ws", get(ws))
+        .with_state(Feed { feed, stop })
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct FeedFilter {
+    // every message if not set
+    message_type: Option<String>,
+}
+
+impl FeedFilter {
+    fn matches(&self, message: &ProcessedMessage) -> bool {
+        self.message_type
+            .as_ref()
+            .is_none_or(|message_type| *message_type == message.message_type)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FeedItem {
+    Message(ProcessedMessage),
+    // the subscriber fell too far behind & missed this many messages
+    Lagged { dropped: u64 },
+}
+
+impl FeedItem {
+    fn event_name(&self) -> &'static str {
+        match self {
+            FeedItem::Message(_) => "message",
+            FeedItem::Lagged { .. } => "lagged",
+        }
+    }
+}
+
+// the messages a subscriber wants, with a notice in place of any it was too slow for
+fn feed_stream(
+    receiver: Receiver<ProcessedMessage>,
+    filter: FeedFilter,
+) -> impl Stream<Item = FeedItem> {
+    stream::unfold(receiver, move |mut receiver| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) if filter.matches(&message) => {
+                        return Some((FeedItem::Message(message), receiver))
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(dropped)) => {
+                        return Some((FeedItem::Lagged { dropped }, receiver))
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
+
+async fn sse(
+    State(Feed { feed, stop }): State<Feed>,
+    Query(filter): Query<FeedFilter>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = feed_stream(feed.subscribe(), filter)
+        .take_until(stop.cancelled_owned())
+        .map(|item| {
+            let event = Event::default().event(item.event_name());
+            // serializing a FeedItem can't fail, it's only strings & json values
+            Ok(event.json_data(&item).unwrap())
+        });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn ws(
+    State(Feed { feed, stop }): State<Feed>,
+    Query(filter): Query<FeedFilter>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // subscribe before upgrading so nothing is missed whilst the upgrade happens
+    let receiver = feed.subscribe();
+    upgrade.on_upgrade(move |socket| send_feed(socket, feed_stream(receiver, filter), stop))
+}
+
+async fn send_feed(
+    socket: WebSocket,
+    items: impl Stream<Item = FeedItem>,
+    stop: CancellationToken,
+) {
+    let (mut sink, mut incoming) = socket.split();
+    let mut items = Box::pin(items);
+
+    loop {
+        select! {
+            _ = stop.cancelled() => {
+                let close = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "server is shutting down".into(),
+                };
+                // the client may already be gone, either way we're done
+                let _ = sink.send(Message::Close(Some(close))).await;
+                break;
+            }
+            item = items.next() => {
+                let Some(item) = item else { break };
+                let json = serde_json::to_string(&item).unwrap();
+                if sink.send(Message::Text(json)).await.is_err() {
+                    break;
+                }
+            }
+            // the client isn't expected to send anything, this is just to notice it
+            // leaving when there's nothing being streamed to it
+            message = incoming.next() => {
+                if matches!(message, None | Some(Err(_)) | Some(Ok(Message::Close(_)))) {
+                    break;
+                }
+            }
+        }
+    }
+    debug!("message feed websocket closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::Request;
+    use hyper::{body::HttpBody, Body};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sse_streams_end_when_shutdown_starts() {
+        let feed = ProcessedFeed::new(2);
+        let stop = CancellationToken::new();
+        let router: Router = router(feed.clone(), stop.clone());
+        let req = Request::get("/sse").body(Body::empty()).unwrap();
+
+        let mut body = router.oneshot(req).await.unwrap().into_body();
+        feed.publish("msg-joseph", &json!({ "age": 1 }));
+        let event = body.data().await.unwrap().unwrap();
+        assert!(event.starts_with(b"event:message"), "{event:?}");
+
+        // the feed is still open, so only shutting down can end the stream
+        stop.cancel();
+        tokio::time::timeout(Duration::from_secs(1), hyper::body::to_bytes(body))
+            .await
+            .expect("stream should end once shutdown starts")
+            .unwrap();
+        drop(feed);
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_are_told_what_they_missed() {
+        let feed = ProcessedFeed::new(2);
+        let filter = FeedFilter {
+            message_type: Some("msg-joseph".to_string()),
+        };
+        let items = feed_stream(feed.subscribe(), filter);
+
+        for (message_type, age) in [
+            ("msg-joseph", 1),
+            ("msg-joseph", 2),
+            ("msg-joseph-2", 3),
+            ("msg-joseph", 4),
+        ] {
+            feed.publish(message_type, &json!({ "age": age }));
+        }
+        drop(feed);
+
+        let items = items.collect::<Vec<_>>().await;
+        assert_eq!(
+            vec![
+                FeedItem::Lagged { dropped: 2 },
+                FeedItem::Message(ProcessedMessage {
+                    message_type: "msg-joseph".to_string(),
+                    message: json!({ "age": 4 }),
+                }),
+            ],
+            items
+        );
+    }
+}
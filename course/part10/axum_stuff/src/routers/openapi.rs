@@ -0,0 +1,210 @@
+use axum::{body::HttpBody, response::Html, routing::get, Router};
+use utoipa::OpenApi;
+
+use super::{BatchLine, BatchOutcome, DivideResult, Numbers};
+use crate::{
+    api_error::{ApiErrorBody, ApiErrorEnvelope, ErrorCode, Json},
+    validation::FieldError,
+};
+
+// the spec for the routes in documented_routes, built from the handlers'
+// #[utoipa::path] attributes & the schemas of their request/response types
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "axum_stuff"),
+    paths(
+        super::one,
+        super::two,
+        super::dynamic_number,
+        super::divide,
+        super::divide2,
+        super::batch,
+        super::long_url,
+    ),
+    components(schemas(
+        Numbers,
+        DivideResult,
+        BatchLine,
+        BatchOutcome,
+        ApiErrorEnvelope,
+        ApiErrorBody,
+        ErrorCode,
+        FieldError,
+    ))
+)]
+pub struct ApiDoc;
+
+// swagger ui is pulled from the unpkg cdn rather than bundled, so the page only works
+// for browsers that can reach unpkg.com. the spec itself is always at /openapi.json
+const DOCS_PAGE: &str = r##"<!doctype html>
+<html>
+  <head>
+    <title>axum_stuff docs</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+      window.onload = () => SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+  </body>
+</html>
+"##;
+
+pub(super) fn router<S, B>() -> Router<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/openapi.json", get(openapi_json))
+        .route("/docs", get(docs))
+}
+
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+async fn docs() -> Html<&'static str> {
+    Html(DOCS_PAGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use axum::{extract::MatchedPath, middleware::Next, response::Response};
+    use http::Request;
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{routers::documented_routes, tower_stuff::PanicCaptureLayer};
+
+    const MATCHED_PATH_HEADER: &str = "x-matched-path";
+
+    // every route in documented_routes, as axum can't list a router's routes this has to
+    // be kept up to date by hand when adding one
+    const ROUTES: &[(&str, &str)] = &[
+        ("GET", "/numbers/1"),
+        ("GET", "/numbers/2"),
+        ("GET", "/numbers/{number}"),
+        ("GET", "/numbers/divide"),
+        ("GET", "/numbers/divide2"),
+        ("POST", "/numbers/batch"),
+        ("GET", "/{a}/{b}"),
+    ];
+
+    fn documented_operations() -> BTreeSet<(String, String)> {
+        ApiDoc::openapi()
+            .paths
+            .paths
+            .iter()
+            .flat_map(|(path, item)| {
+                item.operations.keys().map(move |operation| {
+                    let method = serde_json::to_value(operation)
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                        .to_uppercase();
+                    (method, path.clone())
+                })
+            })
+            .collect()
+    }
+
+    async fn echo_matched_path(request: Request<Body>, next: Next<Body>) -> Response {
+        let matched_path = request.extensions().get::<MatchedPath>().cloned();
+        let mut response = next.run(request).await;
+        if let Some(matched_path) = matched_path {
+            response
+                .headers_mut()
+                .insert(MATCHED_PATH_HEADER, matched_path.as_str().parse().unwrap());
+        }
+        response
+    }
+
+    // /numbers/{number} -> /numbers/5
+    fn example_uri(path: &str) -> String {
+        path.split('/')
+            .map(|segment| {
+                if segment.starts_with('{') {
+                    "5"
+                } else {
+                    segment
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    // /numbers/:number -> /numbers/{number}
+    fn openapi_path(matched_path: &str) -> String {
+        matched_path
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(param) => format!("{{{param}}}"),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[tokio::test]
+    async fn every_documented_operation_is_routed_to_its_own_route() {
+        let router: Router = documented_routes(PanicCaptureLayer::default())
+            .layer(axum::middleware::from_fn(echo_matched_path));
+        let operations = documented_operations();
+        assert!(!operations.is_empty());
+
+        for (method, path) in operations {
+            let req = Request::builder()
+                .method(method.as_str())
+                .uri(example_uri(&path))
+                .body(Body::empty())
+                .unwrap();
+
+            let res = router.clone().oneshot(req).await.unwrap();
+
+            // requests that only matched a route with a wider path, e.g. /{a}/{b},
+            // would still succeed, so check the route that was actually matched
+            let matched = res
+                .headers()
+                .get(MATCHED_PATH_HEADER)
+                .map(|matched| openapi_path(matched.to_str().unwrap()));
+            assert_eq!(Some(&path), matched.as_ref(), "{method} {path}");
+            assert_ne!(405, res.status(), "{method} {path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn every_route_is_documented() {
+        let router: Router = documented_routes(PanicCaptureLayer::default())
+            .layer(axum::middleware::from_fn(echo_matched_path));
+
+        for (method, path) in ROUTES {
+            let req = Request::builder()
+                .method(*method)
+                .uri(example_uri(path))
+                .body(Body::empty())
+                .unwrap();
+
+            let res = router.clone().oneshot(req).await.unwrap();
+
+            // catches ROUTES going stale, a route that's listed but no longer exists
+            let matched = res
+                .headers()
+                .get(MATCHED_PATH_HEADER)
+                .map(|matched| openapi_path(matched.to_str().unwrap()));
+            assert_eq!(Some(*path), matched.as_deref(), "{method} {path}");
+            assert_ne!(405, res.status(), "{method} {path}");
+        }
+
+        let routes = ROUTES
+            .iter()
+            .map(|(method, path)| (method.to_string(), path.to_string()))
+            .collect();
+        assert_eq!(documented_operations(), routes);
+    }
+}
@@ -0,0 +1,301 @@
+//! Config for the server binary, read from `server.toml` with `AXUM_STUFF__` env overrides
+//!
+//! e.g. `AXUM_STUFF__LIMITS__MAX_CONNECTIONS=10` overrides `max_connections` in `[limits]`
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use ::config::{Config, ConfigError, Environment, File};
+use serde::Deserialize;
+
+use crate::tower_stuff::{Quota, ReloadableRate, ResizableSemaphore};
+
+/// Struct containing everything needed to start the server
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub listen_addr: SocketAddr,
+    pub amqp_url: String,
+    // the exchanges, queues & bindings to declare, see rabbit_stuff's topology.toml.
+    // it has to include the queue-joseph queue that's consumed. Rabbit::setup's if not set
+    pub rabbit_topology: Option<PathBuf>,
+    pub limits: LimitsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub shutdown: ShutdownConfig,
+    // plain http if not set
+    pub tls: Option<TlsConfig>,
+    // lets clients inject faults with the x-fault header & change the fault rules at
+    // /admin/faults, without any auth. only for local testing
+    pub fault_injection: bool,
+}
+
+/// Struct containing the server's limits, everything but `max_connections_per_ip` is
+/// reloaded on a SIGHUP
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub connection_rate: RateConfig,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub concurrency_limit: usize,
+}
+
+/// Struct containing how many new connections are accepted every `per_ms`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RateConfig {
+    pub num: u64,
+    pub per_ms: u64,
+}
+
+/// Struct containing how many requests each client can make, changes need a restart
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    #[serde(flatten)]
+    pub default: RateConfig,
+    pub routes: Vec<RouteRateConfig>,
+    // clients sending one of these as their x-api-key are limited by it rather than
+    // their ip
+    pub api_keys: Vec<String>,
+}
+
+/// Struct containing the rate for requests to a route, instead of the default
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RouteRateConfig {
+    pub route: String,
+    #[serde(flatten)]
+    pub rate: RateConfig,
+}
+
+/// Struct containing how the server shuts down
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub drain_timeout_ms: u64,
+}
+
+/// Struct containing the PEM files to serve https with, they're re-read on a SIGHUP
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 25565)),
+            amqp_url: "amqp://localhost:5672".to_string(),
+            rabbit_topology: None,
+            limits: LimitsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            tls: None,
+            fault_injection: false,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            default: RateConfig {
+                num: 100,
+                per_ms: 1000,
+            },
+            routes: vec![RouteRateConfig {
+                route: "/endpoint".to_string(),
+                rate: RateConfig {
+                    num: 5,
+                    per_ms: 60_000,
+                },
+            }],
+            api_keys: Vec::new(),
+        }
+    }
+}
+
+impl RateConfig {
+    pub fn quota(&self) -> Quota {
+        Quota::new(
+            self.num.try_into().unwrap_or(u32::MAX),
+            Duration::from_millis(self.per_ms),
+        )
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_ms: 30_000,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            connection_rate: RateConfig {
+                num: 87654321,
+                per_ms: 1000,
+            },
+            max_connections: 5,
+            max_connections_per_ip: 2,
+            concurrency_limit: 54321,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load_from(src: &str) -> Result<Self, ConfigError> {
+        let config: Self = Config::builder()
+            .add_source(File::with_name(src).required(false))
+            .add_source(
+                Environment::with_prefix("AXUM_STUFF")
+                    .prefix_separator("__")
+                    .separator("__"),
+            )
+            .build()
+            .and_then(Config::try_deserialize)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from("server.toml")
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Message(msg.to_string()));
+        let limits = &self.limits;
+
+        if !self.amqp_url.starts_with("amqp://") && !self.amqp_url.starts_with("amqps://") {
+            return invalid("amqp_url must be an amqp:// or amqps:// url");
+        }
+        if limits.connection_rate.num == 0 || limits.connection_rate.per_ms == 0 {
+            return invalid("limits.connection_rate must allow at least one connection");
+        }
+        if limits.max_connections == 0 || limits.concurrency_limit == 0 {
+            return invalid("limits.max_connections & limits.concurrency_limit can't be 0");
+        }
+        if !(1..=limits.max_connections).contains(&limits.max_connections_per_ip) {
+            return invalid("limits.max_connections_per_ip must be between 1 & max_connections");
+        }
+        let rates = std::iter::once(&self.rate_limit.default)
+            .chain(self.rate_limit.routes.iter().map(|route| &route.rate));
+        for rate in rates {
+            if rate.num == 0 || rate.per_ms == 0 {
+                return invalid("rate_limit rates must allow at least one request");
+            }
+            if rate.num > u64::from(u32::MAX) || rate.num > rate.per_ms.saturating_mul(1_000_000) {
+                return invalid(
+                    "rate_limit rates can't allow more than one request per nanosecond",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // the settings which can't be changed without restarting the server
+    pub fn needs_restart(&self, new: &ServerConfig) -> bool {
+        self.listen_addr != new.listen_addr
+            || self.amqp_url != new.amqp_url
+            || self.rabbit_topology != new.rabbit_topology
+            || self.limits.max_connections_per_ip != new.limits.max_connections_per_ip
+            || self.rate_limit != new.rate_limit
+            || self.shutdown != new.shutdown
+            || self.tls != new.tls
+            || self.fault_injection != new.fault_injection
+    }
+}
+
+/// The handles used to change the limits of a running server
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub connection_rate: ReloadableRate,
+    pub max_connections: ResizableSemaphore,
+    pub concurrency_limit: ResizableSemaphore,
+}
+
+impl Limits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            connection_rate: ReloadableRate::new(
+                config.connection_rate.num,
+                Duration::from_millis(config.connection_rate.per_ms),
+            ),
+            max_connections: ResizableSemaphore::new(config.max_connections),
+            concurrency_limit: ResizableSemaphore::new(config.concurrency_limit),
+        }
+    }
+
+    // connections & requests which are already in flight are left alone, shrinking
+    // limits only stops new ones until enough have finished
+    pub fn apply(&self, config: &LimitsConfig) {
+        self.connection_rate.set(
+            config.connection_rate.num,
+            Duration::from_millis(config.connection_rate.per_ms),
+        );
+        self.max_connections.resize(config.max_connections);
+        self.concurrency_limit.resize(config.concurrency_limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // env vars are shared by the whole process, so tests that load config take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn write_config(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("axum_stuff_{name}_{}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn file_values_are_overridden_by_env() {
+        let path = write_config(
+            "overrides",
+            r#"
+            listen_addr = "0.0.0.0:8080"
+
+            [limits]
+            max_connections = 10
+            concurrency_limit = 100
+            "#,
+        );
+        let _env = ENV.lock().unwrap();
+        std::env::set_var("AXUM_STUFF__LIMITS__CONCURRENCY_LIMIT", "50");
+        let config = ServerConfig::load_from(&path);
+        std::env::remove_var("AXUM_STUFF__LIMITS__CONCURRENCY_LIMIT");
+        let config = config.unwrap();
+
+        assert_eq!(SocketAddr::from(([0, 0, 0, 0], 8080)), config.listen_addr);
+        assert_eq!(10, config.limits.max_connections);
+        assert_eq!(50, config.limits.concurrency_limit);
+        // anything missing uses the defaults
+        assert_eq!(
+            LimitsConfig::default().connection_rate,
+            config.limits.connection_rate
+        );
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let path = write_config(
+            "invalid",
+            r#"
+            [limits]
+            max_connections = 2
+            max_connections_per_ip = 3
+            "#,
+        );
+        let _env = ENV.lock().unwrap();
+        let err = ServerConfig::load_from(&path).unwrap_err();
+        assert!(err.to_string().contains("max_connections_per_ip"), "{err}");
+    }
+}
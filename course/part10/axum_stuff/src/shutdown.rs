@@ -0,0 +1,206 @@
+use std::{future::Future, io, time::Duration};
+
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    time::{interval_at, sleep_until, Instant},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+// how often what's still in flight is logged whilst draining
+const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// resolves with the name of the signal once we've been asked to shut down, our process
+// supervisor sends a SIGTERM & ctrl-c is handy locally
+pub async fn wait_for_signal() -> io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    select! {
+        res = tokio::signal::ctrl_c() => res.map(|()| "ctrl-c"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+// what's still being worked on whilst draining
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InFlight {
+    pub http_requests: i64,
+    pub rabbit_deliveries: usize,
+}
+
+// shuts down in two steps: first `stop` is cancelled so no new connections or
+// deliveries are accepted, then if whatever's in flight hasn't finished by the drain
+// deadline `abort` is cancelled to give up on it
+#[derive(Debug, Clone)]
+pub struct ShutdownCoordinator {
+    drain_timeout: Duration,
+    stop: CancellationToken,
+    abort: CancellationToken,
+    connections: TaskTracker,
+}
+
+impl ShutdownCoordinator {
+    pub fn new(drain_timeout: Duration) -> Self {
+        Self {
+            drain_timeout,
+            stop: CancellationToken::new(),
+            abort: CancellationToken::new(),
+            connections: TaskTracker::new(),
+        }
+    }
+
+    pub fn stop_token(&self) -> CancellationToken {
+        self.stop.clone()
+    }
+
+    pub fn abort_token(&self) -> CancellationToken {
+        self.abort.clone()
+    }
+
+    // for hyper to spawn connections with, so they can be aborted too
+    pub fn connection_executor(&self) -> ConnectionExecutor {
+        ConnectionExecutor {
+            abort: self.abort.clone(),
+            tasks: self.connections.clone(),
+        }
+    }
+
+    // resolves once every task spawned by a connection_executor has finished, which
+    // is straight after aborting as they're dropped rather than run to completion
+    pub async fn connections_closed(&self) {
+        self.connections.close();
+        self.connections.wait().await;
+    }
+
+    // stops accepting new work & waits for `drained`, which should finish once
+    // everything in flight has. returns None if the deadline passed first
+    pub async fn drain<F: Future>(
+        &self,
+        drained: F,
+        in_flight: impl Fn() -> InFlight,
+    ) -> Option<F::Output> {
+        info!(in_flight = ?in_flight(), drain_timeout = ?self.drain_timeout, "draining");
+        self.stop.cancel();
+
+        let deadline = Instant::now() + self.drain_timeout;
+        let mut report = interval_at(
+            Instant::now() + DRAIN_REPORT_INTERVAL,
+            DRAIN_REPORT_INTERVAL,
+        );
+        tokio::pin!(drained);
+
+        loop {
+            select! {
+                output = &mut drained => {
+                    info!("drained");
+                    return Some(output);
+                }
+                _ = sleep_until(deadline) => {
+                    warn!(in_flight = ?in_flight(), "drain deadline passed, aborting");
+                    self.abort.cancel();
+                    return None;
+                }
+                _ = report.tick() => info!(in_flight = ?in_flight(), "still draining"),
+            }
+        }
+    }
+}
+
+// hyper spawns a task for each connection, which aborting the server's own task
+// doesn't touch. spawning them through this drops them, along with the handlers they
+// are running, once the drain deadline passes
+#[derive(Debug, Clone)]
+pub struct ConnectionExecutor {
+    abort: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl<F> hyper::rt::Executor<F> for ConnectionExecutor
+where
+    F: Future + Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        let abort = self.abort.clone();
+        self.tasks.spawn(async move {
+            select! {
+                _ = fut => {}
+                _ = abort.cancelled() => {}
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use hyper::rt::Executor;
+
+    use super::*;
+
+    fn nothing_in_flight() -> InFlight {
+        InFlight {
+            http_requests: 0,
+            rabbit_deliveries: 0,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_stops_new_work_and_waits_for_what_is_in_flight() {
+        let shutdown = ShutdownCoordinator::new(Duration::from_secs(1));
+        let stop = shutdown.stop_token();
+
+        let drained = async {
+            stop.cancelled().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            "done"
+        };
+
+        assert_eq!(
+            Some("done"),
+            shutdown.drain(drained, nothing_in_flight).await
+        );
+        assert!(!shutdown.abort_token().is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn work_still_in_flight_at_the_deadline_is_aborted() {
+        let shutdown = ShutdownCoordinator::new(Duration::from_millis(50));
+
+        let drained = std::future::pending::<()>();
+
+        let start = Instant::now();
+        assert_eq!(None, shutdown.drain(drained, nothing_in_flight).await);
+        assert_eq!(Duration::from_millis(50), start.elapsed());
+        assert!(shutdown.stop_token().is_cancelled());
+        assert!(shutdown.abort_token().is_cancelled());
+    }
+
+    // set when dropped, like a handler that's cancelled
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_still_open_at_the_deadline_are_dropped() {
+        let shutdown = ShutdownCoordinator::new(Duration::from_millis(50));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let connection = DropFlag(dropped.clone());
+        shutdown.connection_executor().execute(async move {
+            std::future::pending::<()>().await;
+            drop(connection);
+        });
+
+        let drained = std::future::pending::<()>();
+        assert_eq!(None, shutdown.drain(drained, nothing_in_flight).await);
+        shutdown.connections_closed().await;
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::extract::connect_info::Connected;
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use hyper::server::accept::Accept;
+use pin_project::pin_project;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate,
+    PrivateKey,
+};
+use rustls_pemfile::Item;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Sleep},
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, info, warn};
+
+use crate::{server_config::TlsConfig, tower_stuff::RemoteAddr};
+
+// clients that haven't finished a handshake by now are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// connections are only accepted whilst fewer than this many are mid handshake, so
+// the connection limit still pushes back on the listener
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+// how long to stop accepting for after an error, e.g. running out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("no certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("invalid private key: {0}")]
+    InvalidKey(#[from] sign::SignError),
+}
+
+// terminates tls for the server, negotiating h2 or http/1.1 with ALPN. the
+// certificate can be reloaded whilst the server is running, clones share it
+#[derive(Clone)]
+pub struct Tls {
+    cert: Arc<ReloadableCert>,
+    acceptor: TlsAcceptor,
+}
+
+impl Tls {
+    pub fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let cert = Arc::new(ReloadableCert {
+            key: RwLock::new(Arc::new(load_certified_key(config)?)),
+            config: config.clone(),
+        });
+
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(cert.clone());
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            cert,
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        })
+    }
+
+    // re-reads the certificate & key, only new connections use them. if they
+    // can't be read the current ones are kept
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let key = load_certified_key(&self.cert.config)?;
+        *self.cert.key.write().unwrap() = Arc::new(key);
+        info!(cert = ?self.cert.config.cert_path, "reloaded tls certificate");
+        Ok(())
+    }
+
+    // connections from the listener once their handshake has finished, for use with
+    // hyper's Server::builder
+    pub fn incoming(&self, listener: TcpListener) -> TlsIncoming {
+        TlsIncoming {
+            listener,
+            acceptor: self.acceptor.clone(),
+            handshakes: FuturesUnordered::new(),
+            backoff: None,
+        }
+    }
+}
+
+struct ReloadableCert {
+    key: RwLock<Arc<CertifiedKey>>,
+    config: TlsConfig,
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey, TlsError> {
+    let mut certs = Vec::new();
+    for item in read_pem(&config.cert_path)? {
+        if let Item::X509Certificate(cert) = item {
+            certs.push(Certificate(cert));
+        }
+    }
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(config.cert_path.clone()));
+    }
+
+    let key = read_pem(&config.key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoPrivateKey(config.key_path.clone()))?;
+
+    Ok(CertifiedKey::new(certs, sign::any_supported_type(&key)?))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, TlsError> {
+    let read = |path: &Path| rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?));
+    read(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// a connection which has finished its tls handshake
+#[pin_project]
+pub struct TlsConnection {
+    #[pin]
+    stream: TlsStream<TcpStream>,
+    remote_addr: SocketAddr,
+}
+
+impl TlsConnection {
+    // what was agreed on with ALPN, if anything
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.stream.get_ref().1.alpn_protocol()
+    }
+}
+
+impl RemoteAddr for TlsConnection {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+// so axum's ConnectInfo works the same as over plain tcp
+impl Connected<&TlsConnection> for SocketAddr {
+    fn connect_info(target: &TlsConnection) -> Self {
+        target.remote_addr
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().stream.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+// accepts tcp connections & runs their handshakes concurrently, so one slow client
+// can't hold up everyone else
+pub struct TlsIncoming {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<BoxFuture<'static, Option<TlsConnection>>>,
+    backoff: Option<Pin<Box<Sleep>>>,
+}
+
+impl TlsIncoming {
+    fn handshake(&self, stream: TcpStream, remote_addr: SocketAddr) {
+        let acceptor = self.acceptor.clone();
+        self.handshakes.push(Box::pin(async move {
+            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => Some(TlsConnection {
+                    stream,
+                    remote_addr,
+                }),
+                Ok(Err(err)) => {
+                    debug!(addr = ?remote_addr, %err, "tls handshake failed");
+                    None
+                }
+                Err(_) => {
+                    debug!(addr = ?remote_addr, "tls handshake timed out");
+                    None
+                }
+            }
+        }));
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsConnection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        if let Some(backoff) = &mut this.backoff {
+            if backoff.as_mut().poll(cx).is_ready() {
+                this.backoff = None;
+            }
+        }
+
+        while this.backoff.is_none() && this.handshakes.len() < MAX_CONCURRENT_HANDSHAKES {
+            match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, remote_addr))) => this.handshake(stream, remote_addr),
+                // errors are about a single connection or temporary, e.g. too many
+                // open files, so they shouldn't take the whole server down
+                Poll::Ready(Err(err)) => {
+                    warn!(%err, "failed to accept a connection");
+                    let mut backoff = Box::pin(tokio::time::sleep(ACCEPT_ERROR_BACKOFF));
+                    // registers the wake up for when it's time to accept again
+                    let _ = backoff.as_mut().poll(cx);
+                    this.backoff = Some(backoff);
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match this.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Some(conn))) => return Poll::Ready(Some(Ok(conn))),
+                // the handshake failed, try the next one
+                Poll::Ready(Some(None)) => continue,
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router, Server};
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::{client, TlsConnector};
+    use tower::Layer;
+
+    use super::*;
+    use crate::tower_stuff::{ConnectionLimitLayer, NewConnSpanMakeServiceLayer};
+
+    // writes a new self signed certificate for localhost, returning its der
+    fn write_self_signed(config: &TlsConfig) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&config.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.key_path, cert.serialize_private_key_pem()).unwrap();
+        // each serialize signs the certificate again, so read back what was written
+        match read_pem(&config.cert_path).unwrap().remove(0) {
+            Item::X509Certificate(der) => der,
+            item => panic!("expected a certificate, got {item:?}"),
+        }
+    }
+
+    fn tls_config(name: &str) -> TlsConfig {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        TlsConfig {
+            cert_path: dir.join(format!("axum_stuff_{name}_{id}_cert.pem")),
+            key_path: dir.join(format!("axum_stuff_{name}_{id}_key.pem")),
+        }
+    }
+
+    async fn serve(tls: &Tls) -> SocketAddr {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let make_service = NewConnSpanMakeServiceLayer.layer(
+            ConnectionLimitLayer::new(1).layer(
+                Router::new()
+                    .route("/", get(|| async { "hi" }))
+                    .into_make_service(),
+            ),
+        );
+        tokio::spawn(Server::builder(tls.incoming(listener)).serve(make_service));
+        addr
+    }
+
+    async fn connect(addr: SocketAddr, trusted: &[u8]) -> client::TlsStream<TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(trusted.to_vec())).unwrap();
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_h2_through_the_connection_layers() {
+        let config = tls_config("h2");
+        let cert = write_self_signed(&config);
+        let addr = serve(&Tls::load(&config).unwrap()).await;
+
+        let stream = connect(addr, &cert).await;
+        assert_eq!(Some(&b"h2"[..]), stream.get_ref().1.alpn_protocol());
+
+        let (mut sender, conn) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(stream)
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let req = http::Request::get(format!("https://localhost:{}/", addr.port()))
+            .body(hyper::Body::empty())
+            .unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        assert_eq!(http::StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn reloaded_certificates_are_used_for_new_connections() {
+        let config = tls_config("reload");
+        write_self_signed(&config);
+        let tls = Tls::load(&config).unwrap();
+        let addr = serve(&tls).await;
+
+        let new_cert = write_self_signed(&config);
+        tls.reload().unwrap();
+
+        let stream = connect(addr, &new_cert).await;
+        let peer_certs = stream.get_ref().1.peer_certificates().unwrap();
+        assert_eq!(new_cert, peer_certs[0].0);
+        // the server only allows one connection at a time
+        drop(stream);
+
+        // a broken key leaves the current certificate in place
+        std::fs::write(&config.key_path, "not a key").unwrap();
+        assert!(matches!(tls.reload(), Err(TlsError::NoPrivateKey(_))));
+        connect(addr, &new_cert).await;
+    }
+}
@@ -1,78 +1,150 @@
 use std::{
-    future::{ready, Future, Ready},
+    future::Future,
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{ready, Context, Poll},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
+use http::{header::RETRY_AFTER, Response};
 use pin_project::pin_project;
-use tokio::time::Sleep;
+use tokio::time::{Instant, Sleep};
 use tower::{
-    retry::{future::ResponseFuture, Policy, Retry, RetryLayer},
+    retry::{budget::Budget, future::ResponseFuture, Policy, Retry},
     Layer,
     Service,
 };
 use tracing::info;
 
-pub struct BackoffLayer<P, B> {
-    retry: RetryLayer<BackoffPolicy<P>>,
+pub struct BackoffLayer<P, B, H = IgnoreRetryAfter> {
+    policy: BackoffPolicy<P, H>,
     backoff: B,
+    deadline: Option<Duration>,
 }
 
 impl<P, B> BackoffLayer<P, B> {
     pub fn new(policy: P, backoff_strategy: B) -> Self {
         Self {
-            retry: RetryLayer::new(BackoffPolicy::new(policy)),
+            policy: BackoffPolicy::new(policy, IgnoreRetryAfter),
             backoff: backoff_strategy,
+            deadline: None,
         }
     }
 }
 
-impl<S, P, B> Layer<S> for BackoffLayer<P, B>
+impl<P, B, H> BackoffLayer<P, B, H> {
+    // every request made through the layer deposits into the budget & every retry
+    // withdraws from it, so retries are limited to a percentage of recent requests
+    // across all services created by this layer
+    pub fn with_retry_budget(mut self, budget: Budget) -> Self {
+        self.policy.budget = Some(Arc::new(budget));
+        self
+    }
+
+    // the total time a request and all of its retries may take. a backoff which
+    // would sleep past this, or an attempt still running when it passes, fails with
+    // BackoffError::DeadlineExceeded instead
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // lets the previous response decide the minimum backoff for the next retry, the
+    // strategy's duration is still used if it is longer than the response's hint
+    pub fn with_retry_after<H2>(self, retry_after: H2) -> BackoffLayer<P, B, H2> {
+        let BackoffLayer {
+            policy,
+            backoff,
+            deadline,
+        } = self;
+        BackoffLayer {
+            policy: BackoffPolicy {
+                inner: policy.inner,
+                budget: policy.budget,
+                retry_after,
+            },
+            backoff,
+            deadline,
+        }
+    }
+}
+
+impl<S, P, B, H> Layer<S> for BackoffLayer<P, B, H>
 where
     P: Clone,
     B: Clone,
+    H: Clone,
 {
-    type Service = BackoffService<P, B, S>;
+    type Service = BackoffService<P, B, S, H>;
 
     fn layer(&self, inner: S) -> Self::Service {
         BackoffService::new(
-            self.retry
-                .layer(BackoffInnerService::new(inner, self.backoff.clone())),
+            Retry::new(
+                self.policy.clone(),
+                BackoffInnerService::new(inner, self.backoff.clone()),
+            ),
+            self.policy.budget.clone(),
+            self.deadline,
         )
     }
 }
 
 #[derive(Clone)]
-pub struct BackoffService<P, B, Req> {
-    inner: Retry<BackoffPolicy<P>, BackoffInnerService<Req, B>>,
+pub struct BackoffService<P, B, Req, H = IgnoreRetryAfter> {
+    inner: Retry<BackoffPolicy<P, H>, BackoffInnerService<Req, B>>,
+    budget: Option<Arc<Budget>>,
+    deadline: Option<Duration>,
 }
 
-impl<P, B, Req> BackoffService<P, B, Req> {
-    fn new(inner: Retry<BackoffPolicy<P>, BackoffInnerService<Req, B>>) -> Self {
-        Self { inner }
+impl<P, B, Req, H> BackoffService<P, B, Req, H> {
+    fn new(
+        inner: Retry<BackoffPolicy<P, H>, BackoffInnerService<Req, B>>,
+        budget: Option<Arc<Budget>>,
+        deadline: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            budget,
+            deadline,
+        }
     }
 }
 
-impl<P, B, S, Req> Service<Req> for BackoffService<P, B, S>
+impl<P, B, S, H, Req> Service<Req> for BackoffService<P, B, S, H>
 where
     P: Policy<Req, S::Response, S::Error> + Clone,
     B: BackoffStrategy,
     S: Service<Req> + Clone,
+    H: RetryAfter<S::Response>,
 {
     type Response = S::Response;
-    type Error = S::Error;
-    type Future = ResponseFuture<BackoffPolicy<P>, BackoffInnerService<S, B>, Backoff<Req>>;
+    type Error = BackoffError<S::Error>;
+    type Future = ResponseFuture<BackoffPolicy<P, H>, BackoffInnerService<S, B>, Backoff<Req>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, req: Req) -> Self::Future {
-        self.inner.call(Backoff::new(req))
+        if let Some(budget) = &self.budget {
+            budget.deposit();
+        }
+        // a deadline too far away to represent is no deadline at all
+        let deadline = self
+            .deadline
+            .and_then(|deadline| Instant::now().checked_add(deadline));
+        self.inner.call(Backoff::new(req, deadline))
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum BackoffError<E> {
+    #[error(transparent)]
+    Inner(E),
+    #[error("the request deadline was exceeded")]
+    DeadlineExceeded,
+}
+
 #[derive(Debug, Clone)]
 pub struct BackoffInnerService<S, B> {
     inner: S,
@@ -91,123 +163,304 @@ where
     B: BackoffStrategy,
 {
     type Response = S::Response;
-    type Error = S::Error;
+    type Error = BackoffError<S::Error>;
     type Future = BackoffFut<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        self.inner.poll_ready(cx).map_err(BackoffError::Inner)
     }
 
     fn call(&mut self, req: Backoff<Req>) -> Self::Future {
-        let Backoff { calls, req } = req;
-        let backoff = self.backoff.backoff_duration(calls);
+        let Backoff { calls, state, req } = req;
         let is_first_call = calls == 0;
-        if !is_first_call {
+        let mut state = state.lock().unwrap();
+        let backoff = if is_first_call {
+            Duration::ZERO
+        } else {
+            let mut backoff = self.backoff.backoff_duration(calls, state.previous);
+            if let Some(retry_after) = state.retry_after.take() {
+                info!("previous response asked for a backoff of {retry_after:?}");
+                backoff = backoff.max(retry_after);
+            }
+            state.previous = backoff;
+
+            // no point sleeping if the call can't be made before the deadline
+            if let Some(deadline) = state.deadline {
+                let wake = Instant::now().checked_add(backoff);
+                if wake.is_none_or(|wake| wake > deadline) {
+                    info!("backoff of {backoff:?} would exceed the request deadline");
+                    return BackoffFut::DeadlineExceeded;
+                }
+            }
+
             info!("this call will backoff for {backoff:?}");
+            backoff
+        };
+
+        let deadline = state.deadline;
+        drop(state);
+
+        BackoffFut::Backoff {
+            slept: is_first_call,
+            sleep: tokio::time::sleep(backoff),
+            // the attempt only gets whatever is left of the deadline
+            deadline: deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline))),
+            fut: self.inner.call(req),
         }
-
-        BackoffFut::new(
-            is_first_call,
-            tokio::time::sleep(backoff),
-            self.inner.call(req),
-        )
     }
 }
 
-#[pin_project]
-pub struct BackoffFut<F> {
-    slept: bool,
-    #[pin]
-    sleep: Sleep,
-    #[pin]
-    fut: F,
+#[pin_project(project = BackoffFutProj)]
+pub enum BackoffFut<F> {
+    DeadlineExceeded,
+    Backoff {
+        slept: bool,
+        #[pin]
+        sleep: Sleep,
+        // boxed as most calls won't have one
+        deadline: Option<Pin<Box<Sleep>>>,
+        #[pin]
+        fut: F,
+    },
 }
 
-impl<F> BackoffFut<F> {
-    fn new(slept: bool, sleep: Sleep, fut: F) -> Self {
-        Self { slept, sleep, fut }
-    }
-}
-
-impl<F> Future for BackoffFut<F>
+impl<F, T, E> Future for BackoffFut<F>
 where
-    F: Future,
+    F: Future<Output = Result<T, E>>,
 {
-    type Output = F::Output;
+    type Output = Result<T, BackoffError<E>>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-
-        if !*this.slept {
-            ready!(this.sleep.poll(cx));
-            info!("backoff complete, trying call...");
-            *this.slept = true;
+        match self.project() {
+            BackoffFutProj::DeadlineExceeded => Poll::Ready(Err(BackoffError::DeadlineExceeded)),
+            BackoffFutProj::Backoff {
+                slept,
+                sleep,
+                deadline,
+                fut,
+            } => {
+                if !*slept {
+                    ready!(sleep.poll(cx));
+                    info!("backoff complete, trying call...");
+                    *slept = true;
+                }
+
+                if let Poll::Ready(res) = fut.poll(cx) {
+                    return Poll::Ready(res.map_err(BackoffError::Inner));
+                }
+                match deadline.as_mut().map(|deadline| deadline.as_mut().poll(cx)) {
+                    Some(Poll::Ready(())) => {
+                        info!("call was still running at the request deadline");
+                        Poll::Ready(Err(BackoffError::DeadlineExceeded))
+                    }
+                    _ => Poll::Pending,
+                }
+            }
         }
-
-        this.fut.poll(cx)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct BackoffPolicy<P> {
+pub struct BackoffPolicy<P, H> {
     inner: P,
+    budget: Option<Arc<Budget>>,
+    retry_after: H,
 }
 
-impl<P> BackoffPolicy<P> {
-    fn new(inner: P) -> Self {
-        Self { inner }
+impl<P, H> BackoffPolicy<P, H> {
+    fn new(inner: P, retry_after: H) -> Self {
+        Self {
+            inner,
+            budget: None,
+            retry_after,
+        }
     }
 }
 
-impl<P, Req, Res, Err> Policy<Backoff<Req>, Res, Err> for BackoffPolicy<P>
+impl<P, H, Req, Res, Err> Policy<Backoff<Req>, Res, BackoffError<Err>> for BackoffPolicy<P, H>
 where
     P: Policy<Req, Res, Err> + Clone,
+    H: RetryAfter<Res>,
 {
-    type Future = Ready<Self>;
+    type Future = BackoffPolicyFut<P::Future, H>;
+
+    fn retry(
+        &self,
+        req: &Backoff<Req>,
+        result: Result<&Res, &BackoffError<Err>>,
+    ) -> Option<Self::Future> {
+        let Backoff { state, req, .. } = req;
+        let result = match result {
+            Ok(res) => Ok(res),
+            Err(BackoffError::Inner(err)) => Err(err),
+            Err(BackoffError::DeadlineExceeded) => return None,
+        };
+        let fut = self.inner.retry(req, result)?;
+
+        if let Some(budget) = &self.budget {
+            if budget.withdraw().is_err() {
+                info!("retry budget is exhausted, not retrying");
+                return None;
+            }
+        }
 
-    fn retry(&self, req: &Backoff<Req>, result: Result<&Res, &Err>) -> Option<Self::Future> {
-        let Backoff { req, .. } = req;
-        self.inner
-            .retry(req, result)
-            .map(|_| ready((*self).clone()))
+        // req is the next attempt, so this is how the response gets to its backoff
+        state.lock().unwrap().retry_after = result
+            .ok()
+            .and_then(|res| self.retry_after.retry_after(res));
+
+        Some(BackoffPolicyFut {
+            fut,
+            budget: self.budget.clone(),
+            retry_after: Some(self.retry_after.clone()),
+        })
     }
 
     fn clone_request(&self, req: &Backoff<Req>) -> Option<Backoff<Req>> {
-        let Backoff { calls, req } = req;
+        let Backoff { calls, state, req } = req;
         self.inner
             .clone_request(req)
-            .map(|req| Backoff::new_with_calls(req, calls + 1))
+            .map(|req| Backoff::new_with_calls(req, calls + 1, state.clone()))
+    }
+}
+
+// wraps the inner policy's future so that the updated inner policy is kept
+#[pin_project]
+pub struct BackoffPolicyFut<F, H> {
+    #[pin]
+    fut: F,
+    budget: Option<Arc<Budget>>,
+    retry_after: Option<H>,
+}
+
+impl<F, P, H> Future for BackoffPolicyFut<F, H>
+where
+    F: Future<Output = P>,
+{
+    type Output = BackoffPolicy<P, H>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.fut.poll(cx));
+        Poll::Ready(BackoffPolicy {
+            inner,
+            budget: this.budget.take(),
+            retry_after: this
+                .retry_after
+                .take()
+                .expect("policy future polled after completion"),
+        })
     }
 }
 
 pub struct Backoff<R> {
     calls: u32,
+    state: Arc<Mutex<BackoffState>>,
     req: R,
 }
 
 impl<R> Backoff<R> {
-    fn new(req: R) -> Self {
-        Self { calls: 0, req }
+    fn new(req: R, deadline: Option<Instant>) -> Self {
+        Self {
+            calls: 0,
+            state: Arc::new(Mutex::new(BackoffState {
+                previous: Duration::ZERO,
+                deadline,
+                retry_after: None,
+            })),
+            req,
+        }
+    }
+    fn new_with_calls(req: R, calls: u32, state: Arc<Mutex<BackoffState>>) -> Self {
+        Self { calls, state, req }
     }
-    fn new_with_calls(req: R, calls: u32) -> Self {
-        Self { calls, req }
+}
+
+// state shared between every attempt of the same logical request, as tower clones
+// the next attempt's request before the current attempt has decided on its backoff
+#[derive(Debug)]
+struct BackoffState {
+    previous: Duration,
+    deadline: Option<Instant>,
+    retry_after: Option<Duration>,
+}
+
+/// Extracts a server provided minimum backoff from a response
+pub trait RetryAfter<Res>: Clone {
+    fn retry_after(&self, res: &Res) -> Option<Duration>;
+}
+
+/// Never takes the backoff from the response, only the strategy is used
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRetryAfter;
+
+impl<Res> RetryAfter<Res> for IgnoreRetryAfter {
+    fn retry_after(&self, _res: &Res) -> Option<Duration> {
+        None
+    }
+}
+
+/// Reads the `Retry-After` header of an http response, which is either a number of
+/// seconds or an http date
+///
+/// The value comes from the server, so it's capped at `max` rather than letting one
+/// response park the request for as long as it likes
+#[derive(Debug, Clone)]
+pub struct HttpRetryAfter {
+    max: Duration,
+}
+
+impl HttpRetryAfter {
+    pub fn new(max: Duration) -> Self {
+        Self { max }
+    }
+}
+
+impl Default for HttpRetryAfter {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60))
+    }
+}
+
+impl<B> RetryAfter<Response<B>> for HttpRetryAfter {
+    fn retry_after(&self, res: &Response<B>) -> Option<Duration> {
+        let retry_after = res.headers().get(RETRY_AFTER)?.to_str().ok()?;
+        let retry_after = match retry_after.trim().parse::<u64>() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => {
+                let date = httpdate::parse_http_date(retry_after).ok()?;
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO)
+            }
+        };
+        Some(retry_after.min(self.max))
     }
 }
 
-trait BackoffStrategy: Clone {
-    fn backoff_duration(&self, repeats: u32) -> Duration;
+/// Decides how long to wait before retry number `repeats` of a request
+///
+/// `previous` is the backoff used for the prior retry of the same request (zero on the
+/// first retry), which most strategies can ignore
+pub trait BackoffStrategy: Clone {
+    fn backoff_duration(&self, repeats: u32, previous: Duration) -> Duration;
 }
 
 pub mod backoff_strategies {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
     use crate::tower_stuff::backoff_layer::BackoffStrategy;
-    use std::time::Duration;
 
     #[derive(Debug, Clone)]
     pub struct ExponentialBackoffStrategy;
 
     impl BackoffStrategy for ExponentialBackoffStrategy {
-        fn backoff_duration(&self, repeats: u32) -> Duration {
-            Duration::from_millis(1 << repeats)
+        fn backoff_duration(&self, repeats: u32, _previous: Duration) -> Duration {
+            Duration::from_millis(2u64.checked_pow(repeats).unwrap_or(u64::MAX))
         }
     }
 
@@ -215,11 +468,11 @@ pub mod backoff_strategies {
     pub struct FibonacciBackoffStrategy;
 
     impl BackoffStrategy for FibonacciBackoffStrategy {
-        fn backoff_duration(&self, repeats: u32) -> Duration {
-            let mut a = 0;
-            let mut b = 1;
+        fn backoff_duration(&self, repeats: u32, _previous: Duration) -> Duration {
+            let mut a: u64 = 0;
+            let mut b: u64 = 1;
             for _ in 0..repeats {
-                let c = a + b;
+                let c = a.saturating_add(b);
                 a = b;
                 b = c;
             }
@@ -239,8 +492,473 @@ pub mod backoff_strategies {
     }
 
     impl BackoffStrategy for LinearBackoffStrategy {
-        fn backoff_duration(&self, repeats: u32) -> Duration {
-            self.duration_multiple * repeats
+        fn backoff_duration(&self, repeats: u32, _previous: Duration) -> Duration {
+            self.duration_multiple
+                .checked_mul(repeats)
+                .unwrap_or(Duration::MAX)
+        }
+    }
+
+    // the decorators below wrap another strategy & adjust the duration it produces,
+    // so they can be stacked e.g. MaxBackoff::new(FullJitter::new(...), max)
+
+    /// Multiplies the wrapped strategy's duration, useful for turning the millisecond
+    /// based strategies above into something more realistic
+    #[derive(Debug, Clone)]
+    pub struct MultipliedBackoff<B> {
+        inner: B,
+        multiplier: u32,
+    }
+
+    impl<B> MultipliedBackoff<B> {
+        pub fn new(inner: B, multiplier: u32) -> Self {
+            Self { inner, multiplier }
+        }
+    }
+
+    impl<B: BackoffStrategy> BackoffStrategy for MultipliedBackoff<B> {
+        fn backoff_duration(&self, repeats: u32, previous: Duration) -> Duration {
+            self.inner
+                .backoff_duration(repeats, previous)
+                .checked_mul(self.multiplier)
+                .unwrap_or(Duration::MAX)
+        }
+    }
+
+    /// Caps the wrapped strategy's duration at `max`
+    #[derive(Debug, Clone)]
+    pub struct MaxBackoff<B> {
+        inner: B,
+        max: Duration,
+    }
+
+    impl<B> MaxBackoff<B> {
+        pub fn new(inner: B, max: Duration) -> Self {
+            Self { inner, max }
+        }
+    }
+
+    impl<B: BackoffStrategy> BackoffStrategy for MaxBackoff<B> {
+        fn backoff_duration(&self, repeats: u32, previous: Duration) -> Duration {
+            self.inner.backoff_duration(repeats, previous).min(self.max)
         }
     }
+
+    // an rng shared between all clones of a jitter strategy. clones of the layer's
+    // strategy end up in every connection's service so a lock is needed
+    #[derive(Debug, Clone)]
+    struct SharedRng(Arc<Mutex<StdRng>>);
+
+    impl SharedRng {
+        fn from_entropy() -> Self {
+            Self(Arc::new(Mutex::new(StdRng::from_entropy())))
+        }
+
+        fn from_seed(seed: u64) -> Self {
+            Self(Arc::new(Mutex::new(StdRng::seed_from_u64(seed))))
+        }
+
+        fn between(&self, low: Duration, high: Duration) -> Duration {
+            if high <= low {
+                return low;
+            }
+            self.0.lock().unwrap().gen_range(low..=high)
+        }
+    }
+
+    /// Picks a random duration between zero and the wrapped strategy's duration
+    #[derive(Debug, Clone)]
+    pub struct FullJitter<B> {
+        inner: B,
+        rng: SharedRng,
+    }
+
+    impl<B> FullJitter<B> {
+        pub fn new(inner: B) -> Self {
+            Self {
+                inner,
+                rng: SharedRng::from_entropy(),
+            }
+        }
+
+        pub fn with_seed(inner: B, seed: u64) -> Self {
+            Self {
+                inner,
+                rng: SharedRng::from_seed(seed),
+            }
+        }
+    }
+
+    impl<B: BackoffStrategy> BackoffStrategy for FullJitter<B> {
+        fn backoff_duration(&self, repeats: u32, previous: Duration) -> Duration {
+            let backoff = self.inner.backoff_duration(repeats, previous);
+            self.rng.between(Duration::ZERO, backoff)
+        }
+    }
+
+    /// Keeps half of the wrapped strategy's duration & randomises the other half
+    #[derive(Debug, Clone)]
+    pub struct EqualJitter<B> {
+        inner: B,
+        rng: SharedRng,
+    }
+
+    impl<B> EqualJitter<B> {
+        pub fn new(inner: B) -> Self {
+            Self {
+                inner,
+                rng: SharedRng::from_entropy(),
+            }
+        }
+
+        pub fn with_seed(inner: B, seed: u64) -> Self {
+            Self {
+                inner,
+                rng: SharedRng::from_seed(seed),
+            }
+        }
+    }
+
+    impl<B: BackoffStrategy> BackoffStrategy for EqualJitter<B> {
+        fn backoff_duration(&self, repeats: u32, previous: Duration) -> Duration {
+            let half = self.inner.backoff_duration(repeats, previous) / 2;
+            half + self.rng.between(Duration::ZERO, half)
+        }
+    }
+
+    /// Picks a random duration between the wrapped strategy's duration and three times
+    /// the previous backoff, so consecutive retries drift apart from other clients
+    #[derive(Debug, Clone)]
+    pub struct DecorrelatedJitter<B> {
+        inner: B,
+        rng: SharedRng,
+    }
+
+    impl<B> DecorrelatedJitter<B> {
+        pub fn new(inner: B) -> Self {
+            Self {
+                inner,
+                rng: SharedRng::from_entropy(),
+            }
+        }
+
+        pub fn with_seed(inner: B, seed: u64) -> Self {
+            Self {
+                inner,
+                rng: SharedRng::from_seed(seed),
+            }
+        }
+    }
+
+    impl<B: BackoffStrategy> BackoffStrategy for DecorrelatedJitter<B> {
+        fn backoff_duration(&self, repeats: u32, previous: Duration) -> Duration {
+            let base = self.inner.backoff_duration(repeats, previous);
+            let high = previous.checked_mul(3).unwrap_or(Duration::MAX);
+            self.rng.between(base, high)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{ready, Ready},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tower::{retry::budget::Budget, service_fn, Layer, Service, ServiceExt};
+
+    use super::{
+        backoff_strategies::*,
+        BackoffError,
+        BackoffLayer,
+        BackoffStrategy,
+        HttpRetryAfter,
+        Policy,
+        RetryAfter,
+    };
+
+    // retries every error forever, so only the budget or deadline can stop it
+    #[derive(Clone)]
+    struct AlwaysRetry;
+
+    impl Policy<(), (), ()> for AlwaysRetry {
+        type Future = Ready<Self>;
+
+        fn retry(&self, _req: &(), result: Result<&(), &()>) -> Option<Self::Future> {
+            result.err().map(|_| ready(AlwaysRetry))
+        }
+
+        fn clone_request(&self, _req: &()) -> Option<()> {
+            Some(())
+        }
+    }
+
+    fn failing_service(
+        calls: Arc<AtomicUsize>,
+    ) -> impl Service<(), Response = (), Error = ()> + Clone {
+        service_fn(move |_| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            ready(Err::<(), ()>(()))
+        })
+    }
+
+    fn durations<B: BackoffStrategy>(strategy: &B, repeats: u32) -> Vec<Duration> {
+        let mut previous = Duration::ZERO;
+        (1..=repeats)
+            .map(|r| {
+                previous = strategy.backoff_duration(r, previous);
+                previous
+            })
+            .collect()
+    }
+
+    #[test]
+    fn built_in_strategies_do_not_overflow() {
+        assert_eq!(
+            Duration::from_millis(u64::MAX),
+            ExponentialBackoffStrategy.backoff_duration(100, Duration::ZERO)
+        );
+        assert_eq!(
+            Duration::from_millis(u64::MAX),
+            FibonacciBackoffStrategy.backoff_duration(200, Duration::ZERO)
+        );
+        assert_eq!(
+            Duration::MAX,
+            LinearBackoffStrategy::new(Duration::MAX).backoff_duration(2, Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn max_backoff_caps_duration() {
+        let strategy = MaxBackoff::new(
+            MultipliedBackoff::new(ExponentialBackoffStrategy, 100),
+            Duration::from_secs(1),
+        );
+        assert_eq!(
+            vec![200, 400, 800, 1000, 1000],
+            durations(&strategy, 5)
+                .into_iter()
+                .map(|d| d.as_millis())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let exponential = MultipliedBackoff::new(ExponentialBackoffStrategy, 100);
+        let full = FullJitter::with_seed(exponential.clone(), 1);
+        let equal = EqualJitter::with_seed(exponential.clone(), 1);
+        for repeats in 1..10 {
+            let max = exponential.backoff_duration(repeats, Duration::ZERO);
+            assert!(full.backoff_duration(repeats, Duration::ZERO) <= max);
+            let equal = equal.backoff_duration(repeats, Duration::ZERO);
+            assert!(max / 2 <= equal && equal <= max);
+        }
+
+        let decorrelated = DecorrelatedJitter::with_seed(exponential.clone(), 1);
+        let mut previous = Duration::ZERO;
+        for repeats in 1..10 {
+            let base = exponential.backoff_duration(repeats, previous);
+            let next = decorrelated.backoff_duration(repeats, previous);
+            assert!(base <= next && next <= (previous * 3).max(base));
+            previous = next;
+        }
+    }
+
+    #[test]
+    fn seeded_jitter_is_deterministic() {
+        let strategy = || {
+            MaxBackoff::new(
+                DecorrelatedJitter::with_seed(
+                    LinearBackoffStrategy::new(Duration::from_millis(50)),
+                    42,
+                ),
+                Duration::from_secs(10),
+            )
+        };
+        assert_eq!(durations(&strategy(), 20), durations(&strategy(), 20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_stops_retries() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = BackoffLayer::new(
+            AlwaysRetry,
+            LinearBackoffStrategy::new(Duration::from_millis(10)),
+        )
+        .with_deadline(Duration::from_millis(50))
+        .layer(failing_service(calls.clone()));
+
+        let res = service.oneshot(()).await;
+        assert!(matches!(res, Err(BackoffError::DeadlineExceeded)));
+        // sleeps of 10ms, 20ms & then 30ms would go past the 50ms deadline
+        assert_eq!(3, calls.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_cancels_a_call_that_is_still_running() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = BackoffLayer::new(AlwaysRetry, ExponentialBackoffStrategy)
+            .with_deadline(Duration::from_millis(50))
+            .layer(service_fn({
+                let calls = calls.clone();
+                move |_| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    async {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Ok::<(), ()>(())
+                    }
+                }
+            }));
+
+        let start = tokio::time::Instant::now();
+        let res = service.oneshot(()).await;
+        assert!(matches!(res, Err(BackoffError::DeadlineExceeded)));
+        assert_eq!(Duration::from_millis(50), start.elapsed());
+        assert_eq!(1, calls.load(Ordering::Relaxed));
+    }
+
+    // retries 503s, as a client would
+    #[derive(Clone)]
+    struct RetryUnavailable;
+
+    impl Policy<(), http::Response<()>, ()> for RetryUnavailable {
+        type Future = Ready<Self>;
+
+        fn retry(
+            &self,
+            _req: &(),
+            result: Result<&http::Response<()>, &()>,
+        ) -> Option<Self::Future> {
+            let unavailable =
+                result.is_ok_and(|res| res.status() == http::StatusCode::SERVICE_UNAVAILABLE);
+            unavailable.then(|| ready(RetryUnavailable))
+        }
+
+        fn clone_request(&self, _req: &()) -> Option<()> {
+            Some(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_waits_for_the_longer_of_the_strategy_and_retry_after() {
+        for (strategy_ms, expected) in [
+            (100, Duration::from_secs(2)),
+            (5_000, Duration::from_secs(5)),
+        ] {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let service = BackoffLayer::new(
+                RetryUnavailable,
+                LinearBackoffStrategy::new(Duration::from_millis(strategy_ms)),
+            )
+            .with_retry_after(HttpRetryAfter::default())
+            .layer(service_fn({
+                let calls = calls.clone();
+                move |_| {
+                    // a 503 asking for 2s, then a 200
+                    let res = match calls.fetch_add(1, Ordering::Relaxed) {
+                        0 => http::Response::builder()
+                            .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                            .header(http::header::RETRY_AFTER, "2"),
+                        _ => http::Response::builder(),
+                    };
+                    ready(Ok::<_, ()>(res.body(()).unwrap()))
+                }
+            }));
+
+            let start = tokio::time::Instant::now();
+            let res = service.oneshot(()).await.unwrap();
+            assert_eq!(http::StatusCode::OK, res.status());
+            assert_eq!(expected, start.elapsed(), "strategy of {strategy_ms}ms");
+            assert_eq!(2, calls.load(Ordering::Relaxed));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn huge_backoffs_and_deadlines_dont_overflow() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = BackoffLayer::new(AlwaysRetry, LinearBackoffStrategy::new(Duration::MAX))
+            .with_deadline(Duration::from_secs(1))
+            .layer(failing_service(calls.clone()));
+        let res = service.oneshot(()).await;
+        assert!(matches!(res, Err(BackoffError::DeadlineExceeded)));
+        assert_eq!(1, calls.load(Ordering::Relaxed));
+
+        let service = BackoffLayer::new(AlwaysRetry, ExponentialBackoffStrategy)
+            .with_deadline(Duration::MAX)
+            .with_retry_budget(Budget::new(Duration::from_secs(1), 0, 0.0))
+            .layer(failing_service(calls.clone()));
+        let res = service.oneshot(()).await;
+        assert!(matches!(res, Err(BackoffError::Inner(()))));
+        assert_eq!(2, calls.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn empty_budget_stops_retries() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = BackoffLayer::new(AlwaysRetry, ExponentialBackoffStrategy)
+            .with_retry_budget(Budget::new(Duration::from_secs(1), 0, 0.0))
+            .layer(failing_service(calls.clone()));
+
+        let res = service.oneshot(()).await;
+        assert!(matches!(res, Err(BackoffError::Inner(()))));
+        assert_eq!(1, calls.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn http_retry_after_parses_seconds_and_dates() {
+        let response = |retry_after: &str| {
+            http::Response::builder()
+                .header(http::header::RETRY_AFTER, retry_after)
+                .body(())
+                .unwrap()
+        };
+
+        assert_eq!(
+            Some(Duration::from_secs(3)),
+            HttpRetryAfter::default().retry_after(&response("3"))
+        );
+        // dates in the past mean the client can retry straight away
+        assert_eq!(
+            Some(Duration::ZERO),
+            HttpRetryAfter::default().retry_after(&response("Wed, 21 Oct 2015 07:28:00 GMT"))
+        );
+        assert_eq!(
+            None,
+            HttpRetryAfter::default().retry_after(&response("soon"))
+        );
+        assert_eq!(
+            None,
+            HttpRetryAfter::default().retry_after(&http::Response::new(()))
+        );
+    }
+
+    #[test]
+    fn http_retry_after_is_capped() {
+        let retry_after = HttpRetryAfter::new(Duration::from_secs(5));
+        let response = |retry_after: &str| {
+            http::Response::builder()
+                .header(http::header::RETRY_AFTER, retry_after)
+                .body(())
+                .unwrap()
+        };
+
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            retry_after.retry_after(&response("99999999"))
+        );
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            retry_after.retry_after(&response("Fri, 31 Dec 9999 23:59:59 GMT"))
+        );
+        assert_eq!(
+            Some(Duration::from_secs(3)),
+            retry_after.retry_after(&response("3"))
+        );
+    }
 }
@@ -0,0 +1,346 @@
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{request::Parts, HeaderMap, Request};
+use http_body::{Empty, Full, SizeHint};
+use hyper::body::HttpBody;
+use pin_project::pin_project;
+use tower::{BoxError, Layer, Service};
+use tracing::info;
+
+// buffers request bodies up to `limit` bytes before sending them on, so that a retry
+// layer such as BackoffLayer further in can replay them. bodies that are larger than
+// the limit are still sent but can't be cloned
+#[derive(Debug, Clone)]
+pub struct BufferedBodyLayer {
+    limit: usize,
+}
+
+impl BufferedBodyLayer {
+    pub fn new(limit: usize) -> Self {
+        Self { limit }
+    }
+}
+
+impl<S> Layer<S> for BufferedBodyLayer {
+    type Service = BufferedBodyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BufferedBodyService {
+            inner,
+            limit: self.limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BufferedBodyService<S> {
+    inner: S,
+    limit: usize,
+}
+
+impl<S, B> Service<Request<B>> for BufferedBodyService<S>
+where
+    S: Service<Request<ReplayBody<B>>> + Clone,
+    B: HttpBody + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BufferedBodyFut<S, B>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // the service that was polled ready is the one that has to be called, so
+        // keep that one and leave a clone in its place
+        let clone = self.inner.clone();
+        let inner = mem::replace(&mut self.inner, clone);
+        let (parts, body) = req.into_parts();
+
+        BufferedBodyFut::Buffering {
+            inner: Some(inner),
+            parts: Some(Box::new(parts)),
+            body: Some(body),
+            buffered: BytesMut::new(),
+            limit: self.limit,
+        }
+    }
+}
+
+#[pin_project(project = BufferedBodyFutProj)]
+pub enum BufferedBodyFut<S, B>
+where
+    S: Service<Request<ReplayBody<B>>>,
+{
+    Buffering {
+        inner: Option<S>,
+        // boxed as Parts is much larger than the future we're waiting on
+        parts: Option<Box<Parts>>,
+        body: Option<B>,
+        buffered: BytesMut,
+        limit: usize,
+    },
+    Calling(#[pin] S::Future),
+}
+
+impl<S, B> Future for BufferedBodyFut<S, B>
+where
+    S: Service<Request<ReplayBody<B>>>,
+    B: HttpBody + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Output = Result<S::Response, S::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            let fut = match self.as_mut().project() {
+                BufferedBodyFutProj::Calling(fut) => return fut.poll(cx),
+                BufferedBodyFutProj::Buffering {
+                    inner,
+                    parts,
+                    body,
+                    buffered,
+                    limit,
+                } => {
+                    let replay_body = ready!(poll_buffer(cx, body, buffered, *limit));
+                    let mut inner = inner.take().expect("polled after completion");
+                    let parts = parts.take().expect("polled after completion");
+                    inner.call(Request::from_parts(*parts, replay_body))
+                }
+            };
+            self.set(BufferedBodyFut::Calling(fut));
+        }
+    }
+}
+
+// reads from the body until it either ends or goes over the limit
+fn poll_buffer<B>(
+    cx: &mut Context<'_>,
+    body: &mut Option<B>,
+    buffered: &mut BytesMut,
+    limit: usize,
+) -> Poll<ReplayBody<B>>
+where
+    B: HttpBody + Unpin,
+    B::Error: Into<BoxError>,
+{
+    let inner = body.as_mut().expect("polled after completion");
+
+    while buffered.len() <= limit {
+        match ready!(Pin::new(&mut *inner).poll_data(cx)) {
+            Some(Ok(mut data)) => {
+                while data.has_remaining() {
+                    let chunk = data.chunk();
+                    let len = chunk.len();
+                    buffered.put_slice(chunk);
+                    data.advance(len);
+                }
+            }
+            Some(Err(error)) => {
+                return Poll::Ready(ReplayBody::Errored {
+                    error: Some(error.into()),
+                })
+            }
+            None => {
+                return match ready!(Pin::new(&mut *inner).poll_trailers(cx)) {
+                    Ok(trailers) => Poll::Ready(ReplayBody::Buffered {
+                        data: Some(mem::take(buffered).freeze()).filter(|data| !data.is_empty()),
+                        trailers,
+                    }),
+                    Err(error) => Poll::Ready(ReplayBody::Errored {
+                        error: Some(error.into()),
+                    }),
+                };
+            }
+        }
+    }
+
+    info!(
+        limit,
+        "request body is over the buffer limit, it will not be retryable"
+    );
+    Poll::Ready(ReplayBody::Partial {
+        buffered: Some(mem::take(buffered).freeze()),
+        rest: body.take().expect("polled after completion"),
+    })
+}
+
+// a request body which has either been fully buffered, or was too large & will be
+// streamed after the part that was already read
+#[derive(Debug)]
+pub enum ReplayBody<B> {
+    Buffered {
+        data: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+    },
+    Partial {
+        buffered: Option<Bytes>,
+        rest: B,
+    },
+    Errored {
+        error: Option<BoxError>,
+    },
+}
+
+impl<B> HttpBody for ReplayBody<B>
+where
+    B: HttpBody + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        match self.get_mut() {
+            ReplayBody::Buffered { data, .. } => Poll::Ready(data.take().map(Ok)),
+            ReplayBody::Partial { buffered, rest } => {
+                if let Some(buffered) = buffered.take() {
+                    return Poll::Ready(Some(Ok(buffered)));
+                }
+                match ready!(Pin::new(rest).poll_data(cx)) {
+                    Some(Ok(mut data)) => {
+                        Poll::Ready(Some(Ok(data.copy_to_bytes(data.remaining()))))
+                    }
+                    Some(Err(error)) => Poll::Ready(Some(Err(error.into()))),
+                    None => Poll::Ready(None),
+                }
+            }
+            ReplayBody::Errored { error } => Poll::Ready(error.take().map(Err)),
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        match self.get_mut() {
+            ReplayBody::Buffered { trailers, .. } => Poll::Ready(Ok(trailers.take())),
+            ReplayBody::Partial { rest, .. } => {
+                Pin::new(rest).poll_trailers(cx).map_err(Into::into)
+            }
+            ReplayBody::Errored { .. } => Poll::Ready(Ok(None)),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            ReplayBody::Buffered { data, trailers } => data.is_none() && trailers.is_none(),
+            ReplayBody::Partial { buffered, rest } => buffered.is_none() && rest.is_end_stream(),
+            ReplayBody::Errored { error } => error.is_none(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            ReplayBody::Buffered { data, .. } => {
+                SizeHint::with_exact(data.as_ref().map_or(0, |data| data.len() as u64))
+            }
+            ReplayBody::Partial { buffered, rest } => {
+                let buffered = buffered.as_ref().map_or(0, |data| data.len() as u64);
+                let rest = rest.size_hint();
+                let mut hint = SizeHint::new();
+                hint.set_lower(rest.lower() + buffered);
+                if let Some(upper) = rest.upper() {
+                    hint.set_upper(upper + buffered);
+                }
+                hint
+            }
+            ReplayBody::Errored { .. } => SizeHint::default(),
+        }
+    }
+}
+
+/// A request body which may be able to be cloned, so that retry policies can replay it
+pub trait TryCloneBody: Sized {
+    fn try_clone(&self) -> Option<Self>;
+}
+
+impl<B> TryCloneBody for ReplayBody<B> {
+    fn try_clone(&self) -> Option<Self> {
+        match self {
+            ReplayBody::Buffered { data, trailers } => Some(ReplayBody::Buffered {
+                data: data.clone(),
+                trailers: trailers.clone(),
+            }),
+            ReplayBody::Partial { .. } | ReplayBody::Errored { .. } => None,
+        }
+    }
+}
+
+impl<D: Buf + Clone> TryCloneBody for Full<D> {
+    fn try_clone(&self) -> Option<Self> {
+        Some(self.clone())
+    }
+}
+
+impl<D> TryCloneBody for Empty<D> {
+    fn try_clone(&self) -> Option<Self> {
+        Some(Empty::new())
+    }
+}
+
+// clones a request if its body can be cloned, for use in retry policies
+pub fn try_clone_request<B: TryCloneBody>(req: &Request<B>) -> Option<Request<B>> {
+    let mut request = Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version())
+        .body(req.body().try_clone()?)
+        .ok()?;
+    *request.headers_mut() = req.headers().clone();
+    Some(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Body;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    async fn buffer(body: Body, limit: usize) -> ReplayBody<Body> {
+        BufferedBodyLayer::new(limit)
+            .layer(service_fn(|req: Request<ReplayBody<Body>>| async move {
+                Ok::<_, hyper::Error>(req.into_body())
+            }))
+            .oneshot(Request::new(body))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn bodies_under_the_limit_can_be_replayed() {
+        let body = Body::wrap_stream(futures::stream::iter(vec![
+            Ok::<_, std::io::Error>("hello "),
+            Ok("world"),
+        ]));
+        let replay = buffer(body, 64).await;
+
+        let clone = replay.try_clone().expect("body should be cloneable");
+        assert_eq!("hello world", hyper::body::to_bytes(replay).await.unwrap());
+        assert_eq!("hello world", hyper::body::to_bytes(clone).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_streamed_but_not_replayable() {
+        let body = Body::wrap_stream(futures::stream::iter(vec![
+            Ok::<_, std::io::Error>("hello "),
+            Ok("world"),
+        ]));
+        let replay = buffer(body, 3).await;
+
+        assert!(replay.try_clone().is_none());
+        assert_eq!("hello world", hyper::body::to_bytes(replay).await.unwrap());
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use http::Response;
+use pin_project::{pin_project, pinned_drop};
+use tokio::time::Instant;
+use tower::{Layer, Service};
+use tracing::{info, warn};
+
+/// Decides whether the outcome of a call counts as a failure for the circuit breaker
+pub trait FailureClassifier<Res, Err>: Clone {
+    fn is_failure(&self, result: Result<&Res, &Err>) -> bool;
+}
+
+// any Fn can be used for one off classifiers
+impl<F, Res, Err> FailureClassifier<Res, Err> for F
+where
+    F: Fn(Result<&Res, &Err>) -> bool + Clone,
+{
+    fn is_failure(&self, result: Result<&Res, &Err>) -> bool {
+        self(result)
+    }
+}
+
+/// Treats errors & 5xx responses as failures, anything else means the dependency is up
+#[derive(Debug, Clone, Default)]
+pub struct HttpFailureClassifier;
+
+impl<B, E> FailureClassifier<Response<B>, E> for HttpFailureClassifier {
+    fn is_failure(&self, result: Result<&Response<B>, &E>) -> bool {
+        match result {
+            Ok(res) => res.status().is_server_error(),
+            Err(_) => true,
+        }
+    }
+}
+
+// clones share the same circuit
+#[derive(Clone)]
+pub struct CircuitBreakerLayer<C> {
+    classifier: C,
+    breaker: Arc<Mutex<CircuitBreaker>>,
+}
+
+impl<C> CircuitBreakerLayer<C> {
+    // all services made by this layer share the same circuit, with the default settings
+    pub fn new(classifier: C) -> Self {
+        Self::builder(classifier).build()
+    }
+
+    pub fn builder(classifier: C) -> CircuitBreakerBuilder<C> {
+        CircuitBreakerBuilder {
+            classifier,
+            breaker: CircuitBreaker::default(),
+        }
+    }
+}
+
+// the circuit's settings can only be changed before it's shared, so configuring one
+// layer can't reconfigure the clones of it
+pub struct CircuitBreakerBuilder<C> {
+    classifier: C,
+    breaker: CircuitBreaker,
+}
+
+impl<C> CircuitBreakerBuilder<C> {
+    // the failure rate (0.0 to 1.0) within the window at which the circuit opens
+    pub fn failure_rate(mut self, failure_rate: f64) -> Self {
+        self.breaker.failure_rate = failure_rate;
+        self
+    }
+
+    // how far back calls are considered when calculating the failure rate
+    pub fn window(mut self, window: Duration) -> Self {
+        self.breaker.window = window;
+        self
+    }
+
+    // the circuit won't open until the window has seen at least this many calls
+    pub fn minimum_calls(mut self, minimum_calls: usize) -> Self {
+        self.breaker.minimum_calls = minimum_calls;
+        self
+    }
+
+    // how long the circuit stays open before letting probe calls through
+    pub fn open_duration(mut self, open_duration: Duration) -> Self {
+        self.breaker.open_duration = open_duration;
+        self
+    }
+
+    // how many probe calls are let through whilst half open, all of them have to
+    // succeed for the circuit to close again
+    pub fn half_open_probes(mut self, half_open_probes: usize) -> Self {
+        self.breaker.half_open_probes = half_open_probes;
+        self
+    }
+
+    pub fn build(self) -> CircuitBreakerLayer<C> {
+        CircuitBreakerLayer {
+            classifier: self.classifier,
+            breaker: Arc::new(Mutex::new(self.breaker)),
+        }
+    }
+}
+
+impl<C, S> Layer<S> for CircuitBreakerLayer<C>
+where
+    C: Clone,
+{
+    type Service = CircuitBreakerService<C, S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreakerService {
+            inner,
+            classifier: self.classifier.clone(),
+            breaker: self.breaker.clone(),
+            probe: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: CircuitState,
+    failure_rate: f64,
+    window: Duration,
+    minimum_calls: usize,
+    open_duration: Duration,
+    half_open_probes: usize,
+    // (time of call completion, was it a failure)
+    calls: VecDeque<(Instant, bool)>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            failure_rate: 0.5,
+            window: Duration::from_secs(10),
+            minimum_calls: 10,
+            open_duration: Duration::from_secs(5),
+            half_open_probes: 3,
+            calls: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum CircuitState {
+    Closed,
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        probes_left: usize,
+        successes: usize,
+    },
+}
+
+impl CircuitBreaker {
+    // returns whether a call may be made & if so, whether that call is a probe
+    fn try_acquire(&mut self) -> Result<bool, CircuitOpen> {
+        if let CircuitState::Open { until } = self.state {
+            if Instant::now() < until {
+                return Err(CircuitOpen);
+            }
+            info!("circuit breaker is now half open");
+            self.state = CircuitState::HalfOpen {
+                probes_left: self.half_open_probes,
+                successes: 0,
+            };
+        }
+
+        match &mut self.state {
+            CircuitState::Closed => Ok(false),
+            CircuitState::HalfOpen { probes_left, .. } if *probes_left > 0 => {
+                *probes_left -= 1;
+                Ok(true)
+            }
+            CircuitState::HalfOpen { .. } | CircuitState::Open { .. } => Err(CircuitOpen),
+        }
+    }
+
+    // gives back a probe that was acquired but never used, or whose call never finished
+    fn release_probe(&mut self) {
+        if let CircuitState::HalfOpen { probes_left, .. } = &mut self.state {
+            *probes_left = (*probes_left + 1).min(self.half_open_probes);
+        }
+    }
+
+    fn record(&mut self, probe: bool, failure: bool) {
+        let now = Instant::now();
+        match self.state {
+            CircuitState::HalfOpen {
+                probes_left,
+                successes,
+            } if probe => {
+                if failure {
+                    warn!("circuit breaker probe failed, opening circuit");
+                    self.open(now);
+                } else if successes + 1 >= self.half_open_probes {
+                    info!("circuit breaker probes succeeded, closing circuit");
+                    self.state = CircuitState::Closed;
+                } else {
+                    self.state = CircuitState::HalfOpen {
+                        probes_left,
+                        successes: successes + 1,
+                    };
+                }
+            }
+            CircuitState::Closed => {
+                self.calls.push_back((now, failure));
+                while let Some((at, _)) = self.calls.front() {
+                    if now.duration_since(*at) <= self.window {
+                        break;
+                    }
+                    self.calls.pop_front();
+                }
+
+                let total = self.calls.len();
+                let failures = self.calls.iter().filter(|(_, failure)| *failure).count();
+                if total >= self.minimum_calls
+                    && failures as f64 / total as f64 >= self.failure_rate
+                {
+                    warn!(failures, total, "failure rate too high, opening circuit");
+                    self.open(now);
+                }
+            }
+            // calls started before the circuit opened don't change anything
+            CircuitState::HalfOpen { .. } | CircuitState::Open { .. } => {}
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = CircuitState::Open {
+            until: now + self.open_duration,
+        };
+        self.calls.clear();
+    }
+}
+
+pub struct CircuitBreakerService<C, S> {
+    inner: S,
+    classifier: C,
+    breaker: Arc<Mutex<CircuitBreaker>>,
+    // whether poll_ready has acquired a half open probe for the next call
+    probe: bool,
+}
+
+// a clone must not share the probe acquired by this service
+impl<C: Clone, S: Clone> Clone for CircuitBreakerService<C, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            classifier: self.classifier.clone(),
+            breaker: self.breaker.clone(),
+            probe: false,
+        }
+    }
+}
+
+impl<C, S> Drop for CircuitBreakerService<C, S> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.lock().unwrap().release_probe();
+        }
+    }
+}
+
+impl<C, S, Req> Service<Req> for CircuitBreakerService<C, S>
+where
+    C: FailureClassifier<S::Response, S::Error>,
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = CircuitBreakerError<S::Error>;
+    type Future = CircuitBreakerFut<C, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if !self.probe {
+            self.probe = self.breaker.lock().unwrap().try_acquire()?;
+        }
+        self.inner
+            .poll_ready(cx)
+            .map_err(CircuitBreakerError::Inner)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        CircuitBreakerFut {
+            fut: self.inner.call(req),
+            classifier: self.classifier.clone(),
+            breaker: self.breaker.clone(),
+            probe: std::mem::take(&mut self.probe),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("circuit breaker is open")]
+pub struct CircuitOpen;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CircuitBreakerError<E> {
+    #[error(transparent)]
+    Inner(E),
+    #[error(transparent)]
+    Open(#[from] CircuitOpen),
+}
+
+// a probe is only recorded once its call finishes, so it's given back if the future is
+// dropped first (e.g. by a timeout), otherwise the circuit would stay half open forever
+#[pin_project(PinnedDrop)]
+pub struct CircuitBreakerFut<C, F> {
+    #[pin]
+    fut: F,
+    classifier: C,
+    breaker: Arc<Mutex<CircuitBreaker>>,
+    probe: bool,
+}
+
+#[pinned_drop]
+impl<C, F> PinnedDrop for CircuitBreakerFut<C, F> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        if *this.probe {
+            this.breaker.lock().unwrap().release_probe();
+        }
+    }
+}
+
+impl<C, F, T, E> Future for CircuitBreakerFut<C, F>
+where
+    C: FailureClassifier<T, E>,
+    F: Future<Output = Result<T, E>>,
+{
+    type Output = Result<T, CircuitBreakerError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let rdy = ready!(this.fut.poll(cx));
+        let failure = this.classifier.is_failure(rdy.as_ref());
+        this.breaker.lock().unwrap().record(*this.probe, failure);
+        *this.probe = false;
+        Poll::Ready(rdy.map_err(CircuitBreakerError::Inner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{pending, ready},
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn breaker(
+        failing: Arc<AtomicBool>,
+    ) -> impl Service<(), Response = (), Error = CircuitBreakerError<()>> + Clone {
+        CircuitBreakerLayer::builder(|result: Result<&(), &()>| result.is_err())
+            .minimum_calls(2)
+            .open_duration(Duration::from_millis(20))
+            .half_open_probes(1)
+            .build()
+            .layer(service_fn(move |_| {
+                ready(if failing.load(Ordering::Relaxed) {
+                    Err(())
+                } else {
+                    Ok(())
+                })
+            }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_failures_and_closes_after_probe() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let service = breaker(failing.clone());
+
+        for _ in 0..2 {
+            let res = service.clone().oneshot(()).await;
+            assert!(matches!(res, Err(CircuitBreakerError::Inner(()))));
+        }
+        let res = service.clone().oneshot(()).await;
+        assert!(matches!(res, Err(CircuitBreakerError::Open(CircuitOpen))));
+
+        failing.store(false, Ordering::Relaxed);
+        tokio::time::advance(Duration::from_millis(30)).await;
+
+        // the single probe closes the circuit again
+        assert!(service.clone().oneshot(()).await.is_ok());
+        assert!(service.clone().oneshot(()).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_probe_reopens_circuit() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let service = breaker(failing.clone());
+
+        for _ in 0..2 {
+            let _ = service.clone().oneshot(()).await;
+        }
+        tokio::time::advance(Duration::from_millis(30)).await;
+
+        let res = service.clone().oneshot(()).await;
+        assert!(matches!(res, Err(CircuitBreakerError::Inner(()))));
+        let res = service.clone().oneshot(()).await;
+        assert!(matches!(res, Err(CircuitBreakerError::Open(CircuitOpen))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_probe_is_given_back() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = CircuitBreakerLayer::builder(|result: Result<&(), &()>| result.is_err())
+            .minimum_calls(1)
+            .open_duration(Duration::from_millis(20))
+            .half_open_probes(1)
+            .build()
+            .layer(service_fn(move |_| {
+                let call = calls.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    match call {
+                        0 => Err(()),
+                        // the first probe never finishes
+                        1 => pending().await,
+                        _ => Ok(()),
+                    }
+                }
+            }));
+
+        let res = service.clone().oneshot(()).await;
+        assert!(matches!(res, Err(CircuitBreakerError::Inner(()))));
+        tokio::time::advance(Duration::from_millis(30)).await;
+
+        let probe = tokio::time::timeout(Duration::from_millis(10), service.clone().oneshot(()));
+        assert!(probe.await.is_err());
+        assert!(service.clone().oneshot(()).await.is_ok());
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::ConnectInfo,
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use http::{header::RETRY_AFTER, HeaderMap, Request, StatusCode};
+use lru::LruCache;
+use tower::{BoxError, Layer, Service};
+use tracing::{debug, warn};
+
+use crate::api_error::{ApiError, ErrorCode};
+
+// clients are identified by this header if it's one of the layer's api keys, otherwise
+// by their ip
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+// how many clients the in memory store remembers before forgetting the least recent
+const IN_MEMORY_CAPACITY: usize = 10_000;
+
+// allows a burst of `num` requests, refilling at a steady rate so that `num` are
+// allowed every `per`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    num: u32,
+    per: Duration,
+}
+
+impl Quota {
+    pub fn new(num: u32, per: Duration) -> Self {
+        assert!(
+            num > 0 && !per.is_zero(),
+            "a quota must allow some requests"
+        );
+        // otherwise the emission interval rounds down to nothing
+        assert!(
+            per.as_nanos() >= u128::from(num),
+            "a quota can't allow more than one request per nanosecond"
+        );
+        Self { num, per }
+    }
+
+    // how often a single request is added back to the bucket
+    fn emission_interval(&self) -> Duration {
+        self.per / self.num
+    }
+
+    // the generic cell rate algorithm, `tat` is the theoretical arrival time a store
+    // keeps for each client: when their bucket will be full again. returns the new
+    // tat if the request is allowed
+    pub fn check(
+        &self,
+        tat: Option<Instant>,
+        now: Instant,
+    ) -> (RateLimitDecision, Option<Instant>) {
+        let tat = tat.map_or(now, |tat| tat.max(now));
+        let new_tat = tat + self.emission_interval();
+
+        if new_tat - now > self.per {
+            let decision = RateLimitDecision {
+                limit: self.num,
+                remaining: 0,
+                reset: tat - now,
+                retry_after: Some(new_tat - now - self.per),
+            };
+            return (decision, None);
+        }
+
+        let spare = self.per - (new_tat - now);
+        let decision = RateLimitDecision {
+            limit: self.num,
+            remaining: (spare.as_nanos() / self.emission_interval().as_nanos()) as u32,
+            reset: new_tat - now,
+            retry_after: None,
+        };
+        (decision, Some(new_tat))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    pub limit: u32,
+    pub remaining: u32,
+    // until the client's bucket is full again
+    pub reset: Duration,
+    // set if the request was rejected, until the next request would be allowed
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitDecision {
+    pub fn allowed(&self) -> bool {
+        self.retry_after.is_none()
+    }
+
+    fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", self.limit.into());
+        headers.insert("ratelimit-remaining", self.remaining.into());
+        headers.insert("ratelimit-reset", ceil_secs(self.reset).into());
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(RETRY_AFTER, ceil_secs(retry_after).into());
+        }
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+// where each client's bucket is kept, e.g. somewhere shared by every instance of the
+// server so a client gets the same limit whichever one it hits
+#[async_trait]
+pub trait RateLimitStore: Send + Sync + 'static {
+    // takes a request from `key`'s bucket if there's one left
+    async fn acquire(&self, key: &str, quota: Quota) -> Result<RateLimitDecision, BoxError>;
+}
+
+// a RateLimitStore that's only shared by this process. it remembers up to `capacity`
+// clients, forgetting the least recent, who then start again with a full bucket
+#[derive(Debug)]
+pub struct InMemoryRateLimitStore {
+    tats: Mutex<LruCache<String, Instant>>,
+}
+
+impl InMemoryRateLimitStore {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            tats: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl Default for InMemoryRateLimitStore {
+    fn default() -> Self {
+        Self::new(NonZeroUsize::new(IN_MEMORY_CAPACITY).unwrap())
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryRateLimitStore {
+    async fn acquire(&self, key: &str, quota: Quota) -> Result<RateLimitDecision, BoxError> {
+        let now = Instant::now();
+        let mut tats = self.tats.lock().unwrap();
+
+        let (decision, new_tat) = quota.check(tats.get(key).copied(), now);
+        if let Some(new_tat) = new_tat {
+            tats.put(key.to_string(), new_tat);
+        }
+        Ok(decision)
+    }
+}
+
+// limits how many requests each client can make, identified by their x-api-key header
+// if it's a known api key, otherwise their ip. routes can have their own quota, which
+// is counted separately from the default one. needs ConnectInfo<SocketAddr> to tell
+// clients without an api key apart, otherwise they all share a bucket
+#[derive(Clone)]
+pub struct ClientRateLimitLayer {
+    store: Arc<dyn RateLimitStore>,
+    default: Quota,
+    routes: Arc<Vec<(String, Quota)>>,
+    api_keys: Arc<HashSet<String>>,
+}
+
+impl ClientRateLimitLayer {
+    pub fn new(store: Arc<dyn RateLimitStore>, default: Quota) -> Self {
+        Self {
+            store,
+            default,
+            routes: Arc::default(),
+            api_keys: Arc::default(),
+        }
+    }
+
+    // the api keys clients are trusted to be identified by, any other key is ignored
+    // so a client can't get a fresh bucket by making one up
+    pub fn api_keys(mut self, api_keys: impl IntoIterator<Item = String>) -> Self {
+        Arc::make_mut(&mut self.api_keys).extend(api_keys);
+        self
+    }
+
+    // matches the request path exactly, or by prefix if it ends with a `*`. the first
+    // matching route is used
+    pub fn route(mut self, route: impl Into<String>, quota: Quota) -> Self {
+        Arc::make_mut(&mut self.routes).push((route.into(), quota));
+        self
+    }
+
+    // the bucket a request is taken from & its quota
+    fn bucket<B>(&self, req: &Request<B>) -> (String, Quota) {
+        let client = self.client_key(req);
+        let path = req.uri().path();
+        let route = self
+            .routes
+            .iter()
+            .find(|(route, _)| match route.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == route,
+            });
+        match route {
+            Some((route, quota)) => (format!("{route} {client}"), *quota),
+            None => (client, self.default),
+        }
+    }
+
+    fn client_key<B>(&self, req: &Request<B>) -> String {
+        let api_key = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|key| key.to_str().ok())
+            .filter(|key| self.api_keys.contains(*key));
+        if let Some(api_key) = api_key {
+            return format!("key:{api_key}");
+        }
+        match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+            None => "unknown".to_string(),
+        }
+    }
+}
+
+impl<S> Layer<S> for ClientRateLimitLayer {
+    type Service = ClientRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientRateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientRateLimit<S> {
+    inner: S,
+    layer: ClientRateLimitLayer,
+}
+
+impl<S, B> Service<Request<B>> for ClientRateLimit<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // the ready service is the one that has to be called
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let store = self.layer.store.clone();
+        let (key, quota) = self.layer.bucket(&req);
+
+        Box::pin(async move {
+            let decision = match store.acquire(&key, quota).await {
+                Ok(decision) => decision,
+                Err(err) => {
+                    // better to let everyone through than no one
+                    warn!(%err, "rate limit store failed, allowing request");
+                    return inner.call(req).await;
+                }
+            };
+
+            let mut res = if decision.allowed() {
+                inner.call(req).await?
+            } else {
+                debug!(key, ?decision, "rate limited");
+                ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    ErrorCode::RateLimited,
+                    format!("more than {} requests in {:?}", quota.num, quota.per),
+                )
+                .into_response()
+            };
+            decision.set_headers(res.headers_mut());
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[test]
+    fn gcra_allows_a_burst_then_refills_steadily() {
+        let quota = Quota::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+
+        let (first, tat) = quota.check(None, start);
+        assert!(first.allowed());
+        assert_eq!(1, first.remaining);
+        let (second, tat) = quota.check(tat, start);
+        assert_eq!(0, second.remaining);
+        assert_eq!(Duration::from_secs(10), second.reset);
+
+        let (third, none) = quota.check(tat, start + Duration::from_secs(1));
+        assert_eq!(None, none);
+        assert_eq!(Some(Duration::from_secs(4)), third.retry_after);
+
+        // one request has been added back after 5s
+        let (fourth, _) = quota.check(tat, start + Duration::from_secs(5));
+        assert!(fourth.allowed());
+        assert_eq!(0, fourth.remaining);
+    }
+
+    #[tokio::test]
+    async fn clients_and_overridden_routes_have_their_own_buckets() {
+        let layer = ClientRateLimitLayer::new(
+            Arc::new(InMemoryRateLimitStore::default()),
+            Quota::new(2, Duration::from_secs(60)),
+        )
+        .route("/endpoint", Quota::new(1, Duration::from_secs(60)))
+        .api_keys(["a".to_string(), "b".to_string()]);
+        let service = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>("ok".into_response())
+        }));
+        let get = |path: &str, api_key: &str| {
+            let req = Request::get(path).header(API_KEY_HEADER, api_key);
+            service.clone().oneshot(req.body(()).unwrap())
+        };
+
+        let res = get("/endpoint", "a").await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("1", res.headers()["ratelimit-limit"]);
+        assert_eq!("0", res.headers()["ratelimit-remaining"]);
+
+        let res = get("/endpoint", "a").await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+        assert_eq!("60", res.headers()[RETRY_AFTER]);
+
+        // other routes & clients aren't affected
+        assert_eq!(StatusCode::OK, get("/hello", "a").await.unwrap().status());
+        assert_eq!(
+            StatusCode::OK,
+            get("/endpoint", "b").await.unwrap().status()
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_api_keys_share_their_ips_bucket() {
+        let layer = ClientRateLimitLayer::new(
+            Arc::new(InMemoryRateLimitStore::default()),
+            Quota::new(1, Duration::from_secs(60)),
+        )
+        .api_keys(["known".to_string()]);
+        let service = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>("ok".into_response())
+        }));
+        let get = |api_key: &str| {
+            let mut req = Request::get("/hello")
+                .header(API_KEY_HEADER, api_key)
+                .body(())
+                .unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 1234))));
+            service.clone().oneshot(req)
+        };
+
+        assert_eq!(StatusCode::OK, get("made-up-1").await.unwrap().status());
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            get("made-up-2").await.unwrap().status()
+        );
+        assert_eq!(StatusCode::OK, get("known").await.unwrap().status());
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_forgets_the_least_recent_client() {
+        let store = InMemoryRateLimitStore::new(NonZeroUsize::new(1).unwrap());
+        let quota = Quota::new(1, Duration::from_secs(60));
+
+        assert!(store.acquire("a", quota).await.unwrap().allowed());
+        assert!(!store.acquire("a", quota).await.unwrap().allowed());
+        assert!(store.acquire("b", quota).await.unwrap().allowed());
+        // a was forgotten to make room for b
+        assert!(store.acquire("a", quota).await.unwrap().allowed());
+    }
+
+    #[test]
+    #[should_panic(expected = "per nanosecond")]
+    fn quotas_finer_than_a_nanosecond_are_rejected() {
+        Quota::new(2_000_000, Duration::from_millis(1));
+    }
+}
@@ -1,25 +1,117 @@
-use hyper::server::conn::AddrStream;
+use futures::future::BoxFuture;
+use http::{
+    header::{CONNECTION, RETRY_AFTER},
+    HeaderValue,
+    Request,
+    Response,
+    StatusCode,
+    Version,
+};
+use lru::LruCache;
 use std::{
+    collections::{HashMap, HashSet},
     future::Future,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
     task::{ready, Context, Poll},
+    time::Duration,
 };
 
 use pin_project::pin_project;
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep},
+};
 use tokio_util::sync::PollSemaphore;
 use tower::{Layer, Service};
-use tracing::info;
+use tracing::{info, warn};
+
+use super::{RemoteAddr, ResizableSemaphore};
 
 pub struct ConnectionLimitLayer {
-    max: usize,
+    sema: Arc<Semaphore>,
+    per_ip: Option<Arc<PerIpLimiter>>,
+    allowlist: Arc<HashSet<IpAddr>>,
+    metrics: ConnectionLimitMetrics,
+    timeouts: ConnectionTimeouts,
+    queue: Option<ConnectionQueue>,
 }
 
 impl ConnectionLimitLayer {
     pub fn new(max: usize) -> Self {
-        Self { max }
+        Self::resizable(ResizableSemaphore::new(max))
+    }
+
+    // the max number of connections can be changed at runtime through the semaphore
+    pub fn resizable(sema: ResizableSemaphore) -> Self {
+        let sema = sema.semaphore();
+        Self {
+            sema: sema.clone(),
+            per_ip: None,
+            allowlist: Default::default(),
+            metrics: ConnectionLimitMetrics {
+                sema,
+                held: Default::default(),
+                overloaded: Default::default(),
+                queued: Default::default(),
+            },
+            timeouts: Default::default(),
+            queue: None,
+        }
+    }
+
+    // limits how many connections a single ip can hold at once, connections past the
+    // limit are dropped straight away. semaphores are kept for the `tracked_ips` most
+    // recently seen addresses, plus any that still have open connections, as forgetting
+    // those would let them go over their limit
+    pub fn per_ip(mut self, max_per_ip: usize, tracked_ips: usize) -> Self {
+        self.per_ip = Some(Arc::new(PerIpLimiter::new(max_per_ip, tracked_ips)));
+        self
+    }
+
+    // connections from these addresses don't count towards either limit. as the
+    // address is only known once the connection is accepted, they will still have to
+    // wait for a permit if the server is at its limit
+    pub fn allow(mut self, allowlist: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.allowlist = Arc::new(allowlist.into_iter().collect());
+        self
+    }
+
+    // closes connections that haven't had a request in flight for this long, so
+    // idle keep-alive clients don't hold on to permits
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.timeouts.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    // closes connections once they've been open this long, in between requests
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.timeouts.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    // rather than stopping accepting connections whilst at the limit, accept them &
+    // let them wait up to `max_wait` for a permit. connections that don't get one
+    // are answered with a 503 & a `retry_after` hint on HTTP/1, or a GOAWAY on
+    // HTTP/2, so clients see that we're overloaded instead of hanging in the backlog.
+    // at most `max_queued` connections wait at once, past that they're told we're
+    // overloaded straight away
+    pub fn queue(mut self, max_wait: Duration, retry_after: Duration, max_queued: usize) -> Self {
+        self.queue = Some(ConnectionQueue {
+            max_wait,
+            retry_after,
+            max_queued,
+        });
+        self
+    }
+
+    pub fn metrics(&self) -> ConnectionLimitMetrics {
+        self.metrics.clone()
     }
 }
 
@@ -29,8 +121,138 @@ impl<S> Layer<S> for ConnectionLimitLayer {
     fn layer(&self, inner: S) -> Self::Service {
         ConnectionLimitService {
             inner,
-            sema: PollSemaphore::new(Arc::new(Semaphore::new(self.max))),
+            sema: PollSemaphore::new(self.sema.clone()),
             permit: None,
+            per_ip: self.per_ip.clone(),
+            allowlist: self.allowlist.clone(),
+            metrics: self.metrics.clone(),
+            timeouts: self.timeouts,
+            queue: self.queue,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ConnectionQueue {
+    max_wait: Duration,
+    retry_after: Duration,
+    max_queued: usize,
+}
+
+impl ConnectionQueue {
+    // don't leave overloaded clients that never send a request hanging around
+    fn overloaded_timeouts(&self) -> ConnectionTimeouts {
+        ConnectionTimeouts {
+            idle_timeout: Some(self.max_wait),
+            max_lifetime: None,
+        }
+    }
+}
+
+// a place in the queue, given back when the connection stops waiting
+struct QueueSlot(Arc<AtomicUsize>);
+
+impl QueueSlot {
+    fn take(queued: &Arc<AtomicUsize>, max_queued: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max_queued).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(queued.clone()))
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct PerIpLimiter {
+    max_per_ip: usize,
+    tracked_ips: usize,
+    semaphores: Mutex<LruCache<IpAddr, Arc<Semaphore>>>,
+}
+
+impl PerIpLimiter {
+    fn new(max_per_ip: usize, tracked_ips: usize) -> Self {
+        Self {
+            max_per_ip,
+            tracked_ips: tracked_ips.max(1),
+            semaphores: Mutex::new(LruCache::unbounded()),
+        }
+    }
+
+    fn try_acquire(&self, ip: IpAddr) -> Option<OwnedSemaphorePermit> {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        if !semaphores.contains(&ip) && semaphores.len() >= self.tracked_ips {
+            self.forget_idle(&mut semaphores);
+        }
+        // whilst locked, so the semaphore can't be forgotten before the permit is taken
+        semaphores
+            .get_or_insert(ip, || Arc::new(Semaphore::new(self.max_per_ip)))
+            .clone()
+            .try_acquire_owned()
+            .ok()
+    }
+
+    // forgets the least recently seen address without any open connections, if there
+    // is one. otherwise every tracked address is connected, so there can only be as
+    // many as the connection limit allows
+    fn forget_idle(&self, semaphores: &mut LruCache<IpAddr, Arc<Semaphore>>) {
+        let idle = semaphores
+            .iter()
+            .rev()
+            .find(|(_, sema)| sema.available_permits() == self.max_per_ip)
+            .map(|(ip, _)| *ip);
+        if let Some(ip) = idle {
+            semaphores.pop(&ip);
+        }
+    }
+}
+
+// a view of the permits currently held by connections, shared by every service
+// made from the same layer
+#[derive(Debug, Clone)]
+pub struct ConnectionLimitMetrics {
+    sema: Arc<Semaphore>,
+    held: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    overloaded: Arc<AtomicU64>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimitMetrics {
+    pub fn available_permits(&self) -> usize {
+        self.sema.available_permits()
+    }
+
+    pub fn held_permits(&self) -> HashMap<IpAddr, usize> {
+        self.held.lock().unwrap().clone()
+    }
+
+    // how many connections have given up waiting for a permit, or found the queue full
+    pub fn overloaded_connections(&self) -> u64 {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    // how many connections are currently waiting for a permit
+    pub fn queued_connections(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    fn acquire(&self, ip: IpAddr) {
+        *self.held.lock().unwrap().entry(ip).or_default() += 1;
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut held = self.held.lock().unwrap();
+        if let Some(count) = held.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                held.remove(&ip);
+            }
         }
     }
 }
@@ -39,20 +261,27 @@ pub struct ConnectionLimitService<S> {
     inner: S,
     sema: PollSemaphore,
     permit: Option<OwnedSemaphorePermit>,
+    per_ip: Option<Arc<PerIpLimiter>>,
+    allowlist: Arc<HashSet<IpAddr>>,
+    metrics: ConnectionLimitMetrics,
+    timeouts: ConnectionTimeouts,
+    queue: Option<ConnectionQueue>,
 }
 
-impl<'a, S> Service<&'a AddrStream> for ConnectionLimitService<S>
+impl<'a, S, T> Service<&'a T> for ConnectionLimitService<S>
 where
-    S: Service<&'a AddrStream>,
+    T: RemoteAddr,
+    S: Service<&'a T>,
 {
     type Response = ConnectionLimitedServiceWrapper<S::Response>;
-    type Error = S::Error;
+    type Error = ConnectionLimitError<S::Error>;
     type Future = ConnectionLimitFut<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         // If we haven't already acquired a permit from the semaphore, try to
-        // acquire one first.
-        if self.permit.is_none() {
+        // acquire one first. When queueing, connections wait for theirs once
+        // they have been accepted instead.
+        if self.permit.is_none() && self.queue.is_none() {
             self.permit = ready!(self.sema.poll_acquire(cx));
 
             debug_assert!(
@@ -64,72 +293,455 @@ where
 
         // Once we've acquired a permit (or if we already had one), poll the
         // inner service.
-        self.inner.poll_ready(cx)
+        self.inner
+            .poll_ready(cx)
+            .map_err(ConnectionLimitError::Inner)
     }
 
-    fn call(&mut self, req: &'a AddrStream) -> Self::Future {
+    fn call(&mut self, req: &'a T) -> Self::Future {
         let permit = self.permit.take();
 
-        debug_assert!(permit.is_some(), "call should always have a permit");
+        debug_assert!(
+            permit.is_some() || self.queue.is_some(),
+            "call should always have a permit"
+        );
+
+        let addr = req.remote_addr();
+        let ip = addr.ip();
+
+        if self.allowlist.contains(&ip) {
+            info!(addr = ?addr, "creating a new connection for an allowlisted address");
+            // give the permit straight back, allowlisted connections don't count
+            drop(permit);
+            return ConnectionLimitFut::Limited {
+                fut: self.inner.call(req),
+                addr,
+                permits: None,
+                timeouts: self.timeouts,
+                queued: None,
+                overloaded: None,
+            };
+        }
+
+        let per_ip_permit = match &self.per_ip {
+            Some(per_ip) => match per_ip.try_acquire(ip) {
+                Some(per_ip_permit) => Some(per_ip_permit),
+                None => {
+                    warn!(addr = ?addr, "too many connections from address, rejecting");
+                    return ConnectionLimitFut::Rejected { ip };
+                }
+            },
+            None => None,
+        };
+
+        if let (None, Some(queue)) = (&permit, self.queue) {
+            let Some(slot) = QueueSlot::take(&self.metrics.queued, queue.max_queued) else {
+                warn!(addr = ?addr, "connection queue is full, telling connection to retry");
+                self.metrics.overloaded.fetch_add(1, Ordering::Relaxed);
+                return ConnectionLimitFut::Limited {
+                    fut: self.inner.call(req),
+                    addr,
+                    permits: None,
+                    timeouts: queue.overloaded_timeouts(),
+                    queued: None,
+                    overloaded: Some(queue.retry_after),
+                };
+            };
+
+            info!(
+                addr = ?addr, available = self.sema.available_permits(),
+                "queueing a new limited connection",
+            );
+
+            let sema = self.metrics.sema.clone();
+            return ConnectionLimitFut::Limited {
+                fut: self.inner.call(req),
+                addr,
+                permits: None,
+                timeouts: self.timeouts,
+                queued: Some(Box::new(Queued {
+                    ip,
+                    wait: Box::pin(async move {
+                        tokio::time::timeout(queue.max_wait, sema.acquire_owned())
+                            .await
+                            .ok()
+                            .and_then(Result::ok)
+                    }),
+                    per_ip_permit,
+                    metrics: self.metrics.clone(),
+                    queue,
+                    _slot: slot,
+                })),
+                overloaded: None,
+            };
+        }
 
         info!(
-            addr = ?req.remote_addr(), available = self.sema.available_permits(),
+            addr = ?addr, available = self.sema.available_permits(),
             "creating a new limited connection",
         );
 
-        ConnectionLimitFut {
+        ConnectionLimitFut::Limited {
             fut: self.inner.call(req),
-            addr: req.remote_addr(),
-            permit,
+            addr,
+            permits: Some(ConnectionPermits::new(
+                ip,
+                permit.expect("call should always have a permit"),
+                per_ip_permit,
+                self.metrics.clone(),
+            )),
+            timeouts: self.timeouts,
+            queued: None,
+            overloaded: None,
         }
     }
 }
 
-#[pin_project]
-pub struct ConnectionLimitFut<F> {
-    #[pin]
-    fut: F,
-    addr: SocketAddr,
-    permit: Option<OwnedSemaphorePermit>,
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionLimitError<E> {
+    #[error(transparent)]
+    Inner(E),
+    #[error("address {0} has too many open connections")]
+    PerIpLimitReached(IpAddr),
+    #[error(transparent)]
+    Closed(#[from] ConnectionClosed),
+}
+
+// closing the connection from poll_ready makes hyper drop it on HTTP/1, and on
+// HTTP/2 send a GOAWAY, which is a graceful one as long as the error carries NO_ERROR
+#[derive(Debug, thiserror::Error)]
+#[error("{reason}")]
+pub struct ConnectionClosed {
+    pub reason: CloseReason,
+    #[source]
+    goaway: h2::Error,
+}
+
+impl From<CloseReason> for ConnectionClosed {
+    fn from(reason: CloseReason) -> Self {
+        Self {
+            reason,
+            goaway: h2::Reason::NO_ERROR.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
+pub enum CloseReason {
+    #[error("connection was idle for too long")]
+    Idle,
+    #[error("connection reached its max lifetime")]
+    MaxLifetime,
+    #[error("connection gave up waiting for a permit")]
+    Overloaded,
+}
+
+// the permits held by a connection, released when the connection is dropped
+pub struct ConnectionPermits {
+    ip: IpAddr,
+    _permit: OwnedSemaphorePermit,
+    _per_ip_permit: Option<OwnedSemaphorePermit>,
+    metrics: ConnectionLimitMetrics,
+}
+
+impl ConnectionPermits {
+    fn new(
+        ip: IpAddr,
+        permit: OwnedSemaphorePermit,
+        per_ip_permit: Option<OwnedSemaphorePermit>,
+        metrics: ConnectionLimitMetrics,
+    ) -> Self {
+        metrics.acquire(ip);
+        Self {
+            ip,
+            _permit: permit,
+            _per_ip_permit: per_ip_permit,
+            metrics,
+        }
+    }
+}
+
+impl Drop for ConnectionPermits {
+    fn drop(&mut self) {
+        self.metrics.release(self.ip);
+    }
+}
+
+#[pin_project(project = ConnectionLimitFutProj)]
+pub enum ConnectionLimitFut<F> {
+    Limited {
+        #[pin]
+        fut: F,
+        addr: SocketAddr,
+        permits: Option<ConnectionPermits>,
+        timeouts: ConnectionTimeouts,
+        // set whilst the connection is waiting for a permit, boxed as most
+        // connections won't need it
+        queued: Option<Box<Queued>>,
+        // the retry after hint, if the connection gave up waiting
+        overloaded: Option<Duration>,
+    },
+    Rejected {
+        ip: IpAddr,
+    },
+}
+
+pub struct Queued {
+    ip: IpAddr,
+    wait: BoxFuture<'static, Option<OwnedSemaphorePermit>>,
+    per_ip_permit: Option<OwnedSemaphorePermit>,
+    metrics: ConnectionLimitMetrics,
+    queue: ConnectionQueue,
+    _slot: QueueSlot,
 }
 
 impl<F, T, E> Future for ConnectionLimitFut<F>
 where
     F: Future<Output = Result<T, E>>,
 {
-    type Output = Result<ConnectionLimitedServiceWrapper<T>, E>;
+    type Output = Result<ConnectionLimitedServiceWrapper<T>, ConnectionLimitError<E>>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        let rdy = ready!(this.fut.poll(cx));
-        Poll::Ready(rdy.map(|inner| ConnectionLimitedServiceWrapper {
-            inner,
-            addr: *this.addr,
-            _permit: this.permit.take().expect("should always have a permit"),
-        }))
+        match self.project() {
+            ConnectionLimitFutProj::Limited {
+                fut,
+                addr,
+                permits,
+                timeouts,
+                queued,
+                overloaded,
+            } => {
+                if let Some(waiting) = queued {
+                    let permit = ready!(waiting.wait.as_mut().poll(cx));
+                    let Queued {
+                        ip,
+                        per_ip_permit,
+                        metrics,
+                        queue,
+                        ..
+                    } = *queued.take().expect("queued was just polled");
+
+                    match permit {
+                        Some(permit) => {
+                            info!(addr = ?addr, "queued connection acquired a permit");
+                            *permits =
+                                Some(ConnectionPermits::new(ip, permit, per_ip_permit, metrics));
+                        }
+                        None => {
+                            warn!(addr = ?addr, "queued connection gave up waiting for a permit");
+                            metrics.overloaded.fetch_add(1, Ordering::Relaxed);
+                            *overloaded = Some(queue.retry_after);
+                            *timeouts = queue.overloaded_timeouts();
+                        }
+                    }
+                }
+
+                let rdy = ready!(fut.poll(cx));
+                Poll::Ready(
+                    rdy.map(|inner| {
+                        ConnectionLimitedServiceWrapper::new(
+                            inner,
+                            *addr,
+                            permits.take(),
+                            *timeouts,
+                            *overloaded,
+                        )
+                    })
+                    .map_err(ConnectionLimitError::Inner),
+                )
+            }
+            ConnectionLimitFutProj::Rejected { ip } => {
+                Poll::Ready(Err(ConnectionLimitError::PerIpLimitReached(*ip)))
+            }
+        }
     }
 }
 
 pub struct ConnectionLimitedServiceWrapper<S> {
     inner: S,
     addr: SocketAddr,
-    _permit: OwnedSemaphorePermit,
+    // None for allowlisted connections
+    _permits: Option<ConnectionPermits>,
+    timeouts: ConnectionTimeouts,
+    created: Instant,
+    activity: Arc<Mutex<Activity>>,
+    // wakes the connection up when it's due to be closed
+    timer: Pin<Box<Sleep>>,
+    overloaded: Option<Overloaded>,
+}
+
+// a connection which didn't get a permit, it answers a single request with a 503
+// then closes
+struct Overloaded {
+    retry_after: HeaderValue,
+    answered: bool,
 }
 
-impl<S, I> Service<I> for ConnectionLimitedServiceWrapper<S>
+impl<S> ConnectionLimitedServiceWrapper<S> {
+    fn new(
+        inner: S,
+        addr: SocketAddr,
+        permits: Option<ConnectionPermits>,
+        timeouts: ConnectionTimeouts,
+        overloaded: Option<Duration>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            addr,
+            _permits: permits,
+            timeouts,
+            created: now,
+            activity: Arc::new(Mutex::new(Activity {
+                in_flight: 0,
+                last_active: now,
+            })),
+            timer: Box::pin(tokio::time::sleep_until(now)),
+            overloaded: overloaded.map(|retry_after| Overloaded {
+                retry_after: retry_after.as_secs().max(1).into(),
+                answered: false,
+            }),
+        }
+    }
+
+    // errors if the connection should be closed, otherwise arms the timer for
+    // when it next might need to be
+    fn poll_timeouts(&mut self, cx: &mut Context<'_>) -> Result<(), ConnectionClosed> {
+        if let Some(Overloaded { answered: true, .. }) = self.overloaded {
+            return Err(CloseReason::Overloaded.into());
+        }
+
+        let now = Instant::now();
+        let mut next_check = None;
+
+        if let Some(max_lifetime) = self.timeouts.max_lifetime {
+            let expires = self.created + max_lifetime;
+            if now >= expires {
+                return Err(CloseReason::MaxLifetime.into());
+            }
+            next_check = Some(expires);
+        }
+
+        if let Some(idle_timeout) = self.timeouts.idle_timeout {
+            let activity = self.activity.lock().unwrap();
+            // requests which are in flight will update last_active when they finish
+            if activity.in_flight == 0 {
+                let idle_from = activity.last_active + idle_timeout;
+                if now >= idle_from {
+                    return Err(CloseReason::Idle.into());
+                }
+                next_check = Some(next_check.map_or(idle_from, |check| check.min(idle_from)));
+            }
+        }
+
+        // hyper polls the service for readiness before reading the next request, so
+        // being woken at the deadline lets us close an idle keep-alive connection
+        if let Some(next_check) = next_check {
+            self.timer.as_mut().reset(next_check);
+            let _ = self.timer.as_mut().poll(cx);
+        }
+
+        Ok(())
+    }
+}
+
+impl<S, B, ResBody> Service<Request<B>> for ConnectionLimitedServiceWrapper<S>
 where
-    S: Service<I>,
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    ResBody: Default,
 {
     type Response = S::Response;
-    type Error = S::Error;
-    type Future = S::Future;
+    type Error = ConnectionLimitError<S::Error>;
+    type Future = ConnectionLimitedFut<S::Future, S::Response>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        if let Err(closed) = self.poll_timeouts(cx) {
+            info!(addr = ?self.addr, "closing connection: {closed}");
+            return Poll::Ready(Err(ConnectionLimitError::Closed(closed)));
+        }
+        self.inner
+            .poll_ready(cx)
+            .map_err(ConnectionLimitError::Inner)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        if let Some(overloaded) = &mut self.overloaded {
+            overloaded.answered = true;
+
+            let mut res = Response::new(ResBody::default());
+            *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            res.headers_mut()
+                .insert(RETRY_AFTER, overloaded.retry_after.clone());
+            // HTTP/2 connections are sent a GOAWAY from poll_ready instead
+            if req.version() <= Version::HTTP_11 {
+                res.headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            return ConnectionLimitedFut::Overloaded { res: Some(res) };
+        }
+
+        self.activity.lock().unwrap().in_flight += 1;
+        ConnectionLimitedFut::Inner {
+            fut: self.inner.call(req),
+            _guard: ActivityGuard {
+                activity: self.activity.clone(),
+            },
+        }
     }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionTimeouts {
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Activity {
+    in_flight: usize,
+    last_active: Instant,
+}
 
-    fn call(&mut self, req: I) -> Self::Future {
-        self.inner.call(req)
+// marks the end of a request on the connection, even if the response is dropped
+pub struct ActivityGuard {
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.in_flight -= 1;
+        activity.last_active = Instant::now();
+    }
+}
+
+#[pin_project(project = ConnectionLimitedFutProj)]
+pub enum ConnectionLimitedFut<F, T> {
+    Inner {
+        #[pin]
+        fut: F,
+        _guard: ActivityGuard,
+    },
+    Overloaded {
+        res: Option<T>,
+    },
+}
+
+impl<F, T, E> Future for ConnectionLimitedFut<F, T>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    type Output = Result<T, ConnectionLimitError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ConnectionLimitedFutProj::Inner { fut, .. } => {
+                fut.poll(cx).map_err(ConnectionLimitError::Inner)
+            }
+            ConnectionLimitedFutProj::Overloaded { res } => {
+                Poll::Ready(Ok(res.take().expect("polled after completion")))
+            }
+        }
     }
 }
 
@@ -141,3 +753,236 @@ impl<S> Drop for ConnectionLimitedServiceWrapper<S> {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use axum::{routing::get, Router, Server};
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    use super::*;
+
+    // serves on a random port with the given layer, returning the address to connect to
+    fn serve(layer: ConnectionLimitLayer) -> SocketAddr {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let make_service = layer.layer(
+            Router::new()
+                .route("/", get(|| async { "hi" }))
+                .into_make_service(),
+        );
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+        addr
+    }
+
+    // raw connections which never send a request, like a slow or misbehaving client
+    async fn connect(addr: SocketAddr, n: usize) -> Vec<TcpStream> {
+        let mut streams = Vec::new();
+        for _ in 0..n {
+            streams.push(TcpStream::connect(addr).await.unwrap());
+        }
+        // give the server a moment to accept them
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        streams
+    }
+
+    async fn assert_closed(stream: &mut TcpStream) {
+        let mut buf = [0; 64];
+        let read = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .expect("connection should have been closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    // the connection is dropped by hyper shortly after the socket is shut down
+    async fn wait_for_release(metrics: &ConnectionLimitMetrics) {
+        for _ in 0..100 {
+            if metrics.held_permits().is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("permits still held: {:?}", metrics.held_permits());
+    }
+
+    // a new connection can only be served if the closed ones gave back their permits
+    async fn assert_can_connect(addr: SocketAddr) {
+        let res = tokio::time::timeout(
+            Duration::from_secs(1),
+            hyper::Client::new().get(format!("http://{addr}/").parse().unwrap()),
+        )
+        .await
+        .expect("no permit was available for a new connection")
+        .unwrap();
+        assert!(res.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn idle_connections_give_back_their_permits() {
+        let layer = ConnectionLimitLayer::new(2).idle_timeout(Duration::from_millis(100));
+        let metrics = layer.metrics();
+        let addr = serve(layer);
+
+        let mut streams = connect(addr, 2).await;
+        assert_eq!(0, metrics.available_permits());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        for stream in &mut streams {
+            assert_closed(stream).await;
+        }
+        wait_for_release(&metrics).await;
+        assert_can_connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn connections_are_closed_after_max_lifetime() {
+        let layer = ConnectionLimitLayer::new(1).max_lifetime(Duration::from_millis(100));
+        let metrics = layer.metrics();
+        let addr = serve(layer);
+
+        let mut streams = connect(addr, 1).await;
+        assert_eq!(0, metrics.available_permits());
+
+        assert_closed(&mut streams[0]).await;
+        wait_for_release(&metrics).await;
+        assert_can_connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn queued_connections_are_served_once_a_permit_frees_up() {
+        let layer =
+            ConnectionLimitLayer::new(1).queue(Duration::from_secs(1), Duration::from_secs(5), 4);
+        let metrics = layer.metrics();
+        let addr = serve(layer);
+
+        let idle = connect(addr, 1).await;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(idle);
+        });
+
+        assert_can_connect(addr).await;
+        assert_eq!(0, metrics.overloaded_connections());
+    }
+
+    #[tokio::test]
+    async fn queued_connections_are_told_to_retry_after_waiting() {
+        let layer = ConnectionLimitLayer::new(1).queue(
+            Duration::from_millis(100),
+            Duration::from_secs(5),
+            4,
+        );
+        let metrics = layer.metrics();
+        let addr = serve(layer);
+        let _idle = connect(addr, 1).await;
+
+        let res = hyper::Client::new()
+            .get(format!("http://{addr}/").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("5", res.headers()[RETRY_AFTER]);
+        assert_eq!("close", res.headers()[CONNECTION]);
+
+        let res = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<hyper::Body>()
+            .get(format!("http://{addr}/").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!(2, metrics.overloaded_connections());
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_queue_length_are_told_to_retry_straight_away() {
+        let layer =
+            ConnectionLimitLayer::new(1).queue(Duration::from_secs(10), Duration::from_secs(5), 2);
+        let metrics = layer.metrics();
+        let addr = serve(layer);
+        let queued = connect(addr, 3).await;
+        // the first connection got the permit, the other two are waiting for it
+        assert_eq!(2, metrics.queued_connections());
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(1),
+            hyper::Client::new().get(format!("http://{addr}/").parse().unwrap()),
+        )
+        .await
+        .expect("a full queue shouldn't make connections wait")
+        .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("5", res.headers()[RETRY_AFTER]);
+        assert_eq!(2, metrics.queued_connections());
+        assert_eq!(1, metrics.overloaded_connections());
+
+        // the queued connections only see they've been closed once they get the permit
+        drop(queued);
+        for _ in 0..100 {
+            if metrics.queued_connections() == 0 && metrics.held_permits().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(0, metrics.queued_connections());
+        assert_can_connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_per_ip_limit_are_rejected() {
+        let layer = ConnectionLimitLayer::new(3).per_ip(2, 16);
+        let metrics = layer.metrics();
+        let addr = serve(layer);
+
+        let mut streams = connect(addr, 3).await;
+        assert_closed(&mut streams[2]).await;
+        // the rejected connection gave its permit back, which the server took again
+        // whilst waiting to accept the next connection
+        assert_eq!(0, metrics.available_permits());
+        assert_eq!(
+            HashMap::from([(IpAddr::from([127, 0, 0, 1]), 2)]),
+            metrics.held_permits()
+        );
+
+        drop(streams);
+        wait_for_release(&metrics).await;
+        assert_eq!(2, metrics.available_permits());
+    }
+
+    #[tokio::test]
+    async fn allowlisted_addresses_dont_count_towards_the_limits() {
+        let layer = ConnectionLimitLayer::new(2)
+            .per_ip(1, 16)
+            .allow([IpAddr::from([127, 0, 0, 1])]);
+        let metrics = layer.metrics();
+        let addr = serve(layer);
+
+        let _streams = connect(addr, 3).await;
+        // only the permit the server holds whilst waiting to accept the next connection
+        assert_eq!(1, metrics.available_permits());
+        assert!(metrics.held_permits().is_empty());
+        assert_can_connect(addr).await;
+    }
+
+    #[test]
+    fn only_addresses_without_connections_are_forgotten() {
+        let limiter = PerIpLimiter::new(1, 1);
+        let (a, b, c) = (
+            IpAddr::from([10, 0, 0, 1]),
+            IpAddr::from([10, 0, 0, 2]),
+            IpAddr::from([10, 0, 0, 3]),
+        );
+
+        let a_permit = limiter.try_acquire(a).unwrap();
+        let _b_permit = limiter.try_acquire(b).unwrap();
+        // a is still connected, so wasn't forgotten to make room for b
+        assert!(limiter.try_acquire(a).is_none());
+
+        drop(a_permit);
+        let _c_permit = limiter.try_acquire(c).unwrap();
+        let semaphores = limiter.semaphores.lock().unwrap();
+        assert!(!semaphores.contains(&a));
+        assert!(semaphores.contains(&b));
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{self, Bytes},
+    response::{IntoResponse, Response},
+};
+use http::{Request, StatusCode};
+use pin_project::pin_project;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::Sleep;
+use tower::{Layer, Service};
+use tracing::info;
+
+use crate::api_error::{ApiError, ErrorCode};
+
+// requests can ask for a fault with this header, if the layer allows it
+pub const FAULT_HEADER: &str = "x-fault";
+
+// the most latency a request can ask for with the header, so a client can't hold on
+// to a connection & a concurrency permit for as long as it likes
+const MAX_HEADER_LATENCY_MS: u64 = 10_000;
+
+// injects errors, latency, aborted connections & specific status codes into requests,
+// either from rules that can be changed at runtime through `FaultRules` or from the
+// x-fault header. only meant for local testing of how clients cope with failures
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjectionLayer {
+    rules: FaultRules,
+    allow_header: bool,
+}
+
+impl FaultInjectionLayer {
+    pub fn new(rules: FaultRules) -> Self {
+        Self {
+            rules,
+            allow_header: false,
+        }
+    }
+
+    // lets clients pick a fault per request, e.g. `x-fault: 503` or `x-fault: latency=200`.
+    // latency is capped at MAX_HEADER_LATENCY_MS
+    pub fn allow_header(mut self) -> Self {
+        self.allow_header = true;
+        self
+    }
+}
+
+impl<S> Layer<S> for FaultInjectionLayer {
+    type Service = FaultInjectionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FaultInjectionService {
+            inner,
+            rules: self.rules.clone(),
+            allow_header: self.allow_header,
+        }
+    }
+}
+
+// the rules used by a FaultInjectionLayer, clones share the same rules so they can
+// be changed whilst the server is running
+#[derive(Debug, Clone, Default)]
+pub struct FaultRules {
+    rules: Arc<RwLock<Vec<FaultRule>>>,
+}
+
+impl FaultRules {
+    pub fn get(&self) -> Vec<FaultRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn set(&self, rules: Vec<FaultRule>) {
+        info!(?rules, "fault injection rules changed");
+        *self.rules.write().unwrap() = rules;
+    }
+
+    // picks the first rule that matches the path & wins its roll of the dice
+    fn pick(&self, path: &str) -> Option<Fault> {
+        let rules = self.rules.read().unwrap();
+        let mut rng = rand::thread_rng();
+        rules
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .find(|rule| rng.gen_bool((rule.percentage / 100.0).clamp(0.0, 1.0)))
+            .map(|rule| rule.fault)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultRule {
+    pub fault: Fault,
+    // matches the request path exactly, or by prefix if it ends with a `*`. every
+    // path is matched if there isn't one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    // the chance out of 100 that a matching request gets the fault
+    #[serde(default = "FaultRule::always")]
+    pub percentage: f64,
+}
+
+impl FaultRule {
+    fn always() -> f64 {
+        100.0
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self.route.as_deref() {
+            None => true,
+            Some(route) => match route.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == route,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    // a 500 with a body saying the fault was injected
+    Error,
+    // responds with this status code instead of calling the service
+    Status(#[serde(with = "status_code")] StatusCode),
+    // waits between min_ms & max_ms before calling the service as normal
+    Latency { min_ms: u64, max_ms: u64 },
+    // starts a response then fails the body, so the connection is aborted
+    Abort,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown fault, expected a status code, `error`, `abort` or `latency=<ms>[-<ms>]`")]
+pub struct UnknownFault;
+
+// the format of the x-fault header
+impl FromStr for Fault {
+    type Err = UnknownFault;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "error" => return Ok(Fault::Error),
+            "abort" => return Ok(Fault::Abort),
+            _ => {}
+        }
+
+        if let Some(latency) = s.strip_prefix("latency=") {
+            let (min, max) = latency.split_once('-').unwrap_or((latency, latency));
+            let min_ms = min.parse().map_err(|_| UnknownFault)?;
+            let max_ms = max.parse().map_err(|_| UnknownFault)?;
+            return Ok(Fault::Latency { min_ms, max_ms });
+        }
+
+        s.parse::<u16>()
+            .ok()
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .map(Fault::Status)
+            .ok_or(UnknownFault)
+    }
+}
+
+impl Fault {
+    fn capped(self) -> Self {
+        match self {
+            Fault::Latency { min_ms, max_ms } => Fault::Latency {
+                min_ms: min_ms.min(MAX_HEADER_LATENCY_MS),
+                max_ms: max_ms.min(MAX_HEADER_LATENCY_MS),
+            },
+            fault => fault,
+        }
+    }
+
+    fn response(self) -> Response {
+        match self {
+            Fault::Error => Fault::Status(StatusCode::INTERNAL_SERVER_ERROR).response(),
+            Fault::Status(status) => {
+                ApiError::new(status, ErrorCode::InjectedFault, "injected fault").into_response()
+            }
+            Fault::Abort => {
+                let body = hyper::Body::wrap_stream(futures::stream::once(async {
+                    Err::<Bytes, _>(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "injected abort",
+                    ))
+                }));
+                Response::new(body::boxed(body))
+            }
+            Fault::Latency { .. } => unreachable!("latency calls the inner service"),
+        }
+    }
+}
+
+mod status_code {
+    use http::StatusCode;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(status: &StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(status.as_u16())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StatusCode, D::Error> {
+        StatusCode::from_u16(u16::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FaultInjectionService<S> {
+    inner: S,
+    rules: FaultRules,
+    allow_header: bool,
+}
+
+impl<S, B> Service<Request<B>> for FaultInjectionService<S>
+where
+    S: Service<Request<B>, Response = Response>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = FaultFut<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let from_header = self
+            .allow_header
+            .then(|| {
+                let fault = req.headers().get(FAULT_HEADER)?.to_str().ok()?;
+                Some(fault.parse::<Fault>().ok()?.capped())
+            })
+            .flatten();
+        let Some(fault) = from_header.or_else(|| self.rules.pick(req.uri().path())) else {
+            return FaultFut::Fut(self.inner.call(req));
+        };
+
+        info!(?fault, path = req.uri().path(), "injecting fault");
+        match fault {
+            Fault::Latency { min_ms, max_ms } => {
+                let millis = rand::thread_rng().gen_range(min_ms..=max_ms.max(min_ms));
+                FaultFut::Delayed {
+                    sleep: tokio::time::sleep(Duration::from_millis(millis)),
+                    slept: false,
+                    fut: self.inner.call(req),
+                }
+            }
+            fault => FaultFut::Injected {
+                response: Some(fault.response()),
+            },
+        }
+    }
+}
+
+#[pin_project(project = FaultFutProjection)]
+pub enum FaultFut<F> {
+    Injected {
+        response: Option<Response>,
+    },
+    Delayed {
+        #[pin]
+        sleep: Sleep,
+        slept: bool,
+        #[pin]
+        fut: F,
+    },
+    Fut(#[pin] F),
+}
+
+impl<F, E> Future for FaultFut<F>
+where
+    F: Future<Output = Result<Response, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            FaultFutProjection::Injected { response } => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+            FaultFutProjection::Delayed { sleep, slept, fut } => {
+                if !*slept {
+                    ready!(sleep.poll(cx));
+                    *slept = true;
+                }
+                fut.poll(cx)
+            }
+            FaultFutProjection::Fut(fut) => fut.poll(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn service(
+        layer: FaultInjectionLayer,
+    ) -> impl Service<Request<()>, Response = Response, Error = Infallible> + Clone {
+        layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>("ok".into_response())
+        }))
+    }
+
+    fn get(path: &str, fault: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().uri(path);
+        if let Some(fault) = fault {
+            req = req.header(FAULT_HEADER, fault);
+        }
+        req.body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rules_match_routes_and_can_be_changed_at_runtime() {
+        let rules = FaultRules::default();
+        let service = service(FaultInjectionLayer::new(rules.clone()));
+
+        let res = service
+            .clone()
+            .oneshot(get("/numbers/1", None))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        rules.set(serde_json::from_str(
+            r#"[{"fault": {"status": 503}, "route": "/numbers/*"}, {"fault": "error", "percentage": 0}]"#,
+        ).unwrap());
+
+        let res = service
+            .clone()
+            .oneshot(get("/numbers/1", None))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("injected_fault", body["error"]["code"]);
+        let res = service.clone().oneshot(get("/hello", None)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn header_faults_are_only_used_when_allowed() {
+        let res = service(FaultInjectionLayer::default())
+            .oneshot(get("/hello", Some("418")))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let service = service(FaultInjectionLayer::default().allow_header());
+        let res = service
+            .clone()
+            .oneshot(get("/hello", Some("418")))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::IM_A_TEAPOT, res.status());
+
+        let res = service.oneshot(get("/hello", Some("abort"))).await.unwrap();
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn header_latency_is_capped() {
+        let service = service(FaultInjectionLayer::default().allow_header());
+        let start = tokio::time::Instant::now();
+        let res = service
+            .oneshot(get("/hello", Some("latency=18446744073709551615")))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            Duration::from_millis(MAX_HEADER_LATENCY_MS),
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn parses_header_values() {
+        assert_eq!(Fault::Error, "error".parse().unwrap());
+        assert_eq!(
+            Fault::Status(StatusCode::BAD_GATEWAY),
+            "502".parse().unwrap()
+        );
+        assert_eq!(
+            Fault::Latency {
+                min_ms: 100,
+                max_ms: 250
+            },
+            "latency=100-250".parse().unwrap()
+        );
+        assert!("latency=soon".parse::<Fault>().is_err());
+        assert!("1000".parse::<Fault>().is_err());
+    }
+}
@@ -1,11 +1,62 @@
 mod backoff_layer;
+mod buffered_body_layer;
+mod circuit_breaker_layer;
+mod client_rate_limit_layer;
 mod connection_limit_layer;
-mod every_other_layer;
+mod fault_injection_layer;
 mod new_conn_span_layer;
 mod panic_capture_layer;
+mod reloadable_rate_limit_layer;
+mod remote_addr;
+mod request_id_layer;
+mod resizable_semaphore;
+mod response_cache_layer;
 
-pub use backoff_layer::{backoff_strategies, BackoffLayer};
-pub use connection_limit_layer::ConnectionLimitLayer;
-pub use every_other_layer::EveryOtherRequestLayer;
+pub use backoff_layer::{
+    backoff_strategies,
+    BackoffError,
+    BackoffLayer,
+    BackoffStrategy,
+    HttpRetryAfter,
+    IgnoreRetryAfter,
+    RetryAfter,
+};
+pub use buffered_body_layer::{try_clone_request, BufferedBodyLayer, ReplayBody, TryCloneBody};
+pub use circuit_breaker_layer::{
+    CircuitBreakerBuilder,
+    CircuitBreakerError,
+    CircuitBreakerLayer,
+    CircuitOpen,
+    FailureClassifier,
+    HttpFailureClassifier,
+};
+pub use client_rate_limit_layer::{
+    ClientRateLimitLayer,
+    InMemoryRateLimitStore,
+    Quota,
+    RateLimitDecision,
+    RateLimitStore,
+    API_KEY_HEADER,
+};
+pub use connection_limit_layer::{
+    CloseReason,
+    ConnectionClosed,
+    ConnectionLimitError,
+    ConnectionLimitLayer,
+    ConnectionLimitMetrics,
+};
+pub use fault_injection_layer::{
+    Fault,
+    FaultInjectionLayer,
+    FaultRule,
+    FaultRules,
+    UnknownFault,
+    FAULT_HEADER,
+};
 pub use new_conn_span_layer::NewConnSpanMakeServiceLayer;
 pub use panic_capture_layer::PanicCaptureLayer;
+pub use reloadable_rate_limit_layer::{ReloadableRate, ReloadableRateLimitLayer};
+pub use remote_addr::RemoteAddr;
+pub use request_id_layer::{RequestId, RequestIdLayer, REQUEST_ID_HEADER};
+pub use resizable_semaphore::ResizableSemaphore;
+pub use response_cache_layer::ResponseCacheLayer;
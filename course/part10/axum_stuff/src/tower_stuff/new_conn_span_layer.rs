@@ -4,11 +4,12 @@ use std::{
     task::{ready, Context, Poll},
 };
 
-use hyper::server::conn::AddrStream;
 use pin_project::pin_project;
 use tower::{Layer, Service};
 use tracing::{debug, debug_span, Span};
 
+use super::RemoteAddr;
+
 #[derive(Debug)]
 pub struct NewConnSpanMakeServiceLayer;
 
@@ -24,9 +25,10 @@ pub struct NewConnSpanMakeService<S> {
     inner: S,
 }
 
-impl<'a, S> Service<&'a AddrStream> for NewConnSpanMakeService<S>
+impl<'a, S, T> Service<&'a T> for NewConnSpanMakeService<S>
 where
-    S: Service<&'a AddrStream>,
+    T: RemoteAddr,
+    S: Service<&'a T>,
 {
     type Response = SpannedService<S::Response>;
     type Error = S::Error;
@@ -43,7 +45,7 @@ where
         poll
     }
 
-    fn call(&mut self, req: &'a AddrStream) -> Self::Future {
+    fn call(&mut self, req: &'a T) -> Self::Future {
         let span = debug_span!("connection", addr=?req.remote_addr());
         NewConnSpanFut {
             span,
@@ -1,33 +1,75 @@
 use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
     future::Future,
+    panic::{self, AssertUnwindSafe},
     pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Once,
+    },
     task::{Context, Poll},
 };
 
-use axum::response::{IntoResponse, Response};
-use http::StatusCode;
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header::CONTENT_TYPE, HeaderValue, Request, StatusCode};
 use pin_project::pin_project;
+use serde::Serialize;
 use tower::{Layer, Service};
+use tracing::error;
+use uuid::Uuid;
+
+use super::REQUEST_ID_HEADER;
 
+// turns panics in the inner service into 500 application/problem+json responses.
+// clones share the same panic counter
 #[derive(Debug, Clone, Default)]
-pub struct PanicCaptureLayer;
+pub struct PanicCaptureLayer {
+    panics: Arc<AtomicU64>,
+    backtrace: bool,
+}
+
+impl PanicCaptureLayer {
+    // includes the backtrace in the response body, only has an effect in debug builds
+    // so that release builds never leak one to clients
+    pub fn with_backtrace(mut self) -> Self {
+        self.backtrace = cfg!(debug_assertions);
+        self
+    }
+
+    // how many panics have been captured by services made from this layer
+    pub fn panic_count(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+}
 
 impl<S> Layer<S> for PanicCaptureLayer {
     type Service = PanicCaptureService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        PanicCaptureService { inner }
+        PanicCaptureService {
+            inner,
+            panics: self.panics.clone(),
+            backtrace: self.backtrace,
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct PanicCaptureService<S> {
     inner: S,
+    panics: Arc<AtomicU64>,
+    backtrace: bool,
 }
 
-impl<S, I> Service<I> for PanicCaptureService<S>
+impl<S, B> Service<Request<B>> for PanicCaptureService<S>
 where
-    S: Service<I, Response = Response>,
+    S: Service<Request<B>, Response = Response>,
 {
     type Response = S::Response;
     type Error = S::Error;
@@ -37,18 +79,92 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: I) -> Self::Future {
-        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.inner.call(req))) {
-            Ok(fut) => PanicCaptureFut::Fut(fut),
-            Err(_) => PanicCaptureFut::Panicked,
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // the request id is used as the correlation id, otherwise a new one is made
+        let context = PanicContext {
+            correlation_id: req
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|id| id.to_str().ok())
+                .map_or_else(|| Uuid::new_v4().to_string(), str::to_string),
+            instance: req.uri().path().to_string(),
+            panics: self.panics.clone(),
+            backtrace: self.backtrace,
+        };
+
+        match capture(self.backtrace, || self.inner.call(req)) {
+            Ok(fut) => PanicCaptureFut::Fut { fut, context },
+            Err(panicked) => PanicCaptureFut::Panicked {
+                response: Some(context.respond(panicked)),
+            },
         }
     }
 }
 
+// what's needed to report a panic for a single request
+#[derive(Debug)]
+pub struct PanicContext {
+    correlation_id: String,
+    instance: String,
+    panics: Arc<AtomicU64>,
+    backtrace: bool,
+}
+
+impl PanicContext {
+    fn respond(&self, panicked: CapturedPanic) -> Response {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        error!(
+            correlation_id = %self.correlation_id,
+            panic.message = %panicked.message,
+            panic.location = panicked.location.as_deref().unwrap_or("unknown"),
+            "request handler panicked",
+        );
+
+        let problem = Problem {
+            type_: "about:blank",
+            title: "Internal Server Error",
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            detail: "the request handler panicked",
+            instance: &self.instance,
+            correlation_id: &self.correlation_id,
+            backtrace: panicked.backtrace.filter(|_| self.backtrace),
+        };
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            )],
+            Json(problem),
+        )
+            .into_response()
+    }
+}
+
+// an RFC 7807 problem details body
+#[derive(Debug, Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: &'static str,
+    instance: &'a str,
+    correlation_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backtrace: Option<String>,
+}
+
 #[pin_project(project = PanicCaptureFutProjection)]
 pub enum PanicCaptureFut<F> {
-    Panicked,
-    Fut(#[pin] F),
+    Panicked {
+        response: Option<Response>,
+    },
+    Fut {
+        #[pin]
+        fut: F,
+        context: PanicContext,
+    },
 }
 
 impl<F, E> Future for PanicCaptureFut<F>
@@ -59,21 +175,124 @@ where
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.project() {
-            PanicCaptureFutProjection::Panicked => Poll::Ready(Ok((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "PanicCaptureService::call panicked",
-            )
-                .into_response())),
-            PanicCaptureFutProjection::Fut(fut) => {
-                match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| fut.poll(cx))) {
+            PanicCaptureFutProjection::Panicked { response } => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+            PanicCaptureFutProjection::Fut { fut, context } => {
+                match capture(context.backtrace, || fut.poll(cx)) {
                     Ok(polled) => polled,
-                    Err(_) => Poll::Ready(Ok((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "PanicCaptureFut::poll panicked",
-                    )
-                        .into_response())),
+                    Err(panicked) => Poll::Ready(Ok(context.respond(panicked))),
                 }
             }
         }
     }
 }
+
+#[derive(Debug)]
+struct CapturedPanic {
+    message: String,
+    location: Option<String>,
+    backtrace: Option<String>,
+}
+
+thread_local! {
+    // set whilst running code that may panic, the value is whether to take a backtrace
+    static CAPTURING: Cell<Option<bool>> = const { Cell::new(None) };
+    // filled in by the panic hook, the payload alone doesn't tell us where it happened
+    static CAPTURED: RefCell<Option<CapturedPanic>> = const { RefCell::new(None) };
+}
+
+// runs f, catching any panic along with where it happened. the hook that was
+// installed before is still called for every panic, caught or not
+fn capture<R>(backtrace: bool, f: impl FnOnce() -> R) -> Result<R, CapturedPanic> {
+    static INSTALL_HOOK: Once = Once::new();
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if let Some(backtrace) = CAPTURING.with(Cell::get) {
+                CAPTURED.with(|captured| {
+                    *captured.borrow_mut() = Some(CapturedPanic {
+                        message: String::new(),
+                        location: info.location().map(ToString::to_string),
+                        backtrace: backtrace.then(|| Backtrace::force_capture().to_string()),
+                    })
+                });
+            }
+            previous(info);
+        }));
+    });
+
+    // restored afterwards as captures can be nested
+    let outer = CAPTURING.with(|capturing| capturing.replace(Some(backtrace)));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CAPTURING.with(|capturing| capturing.set(outer));
+
+    result.map_err(|payload| {
+        let mut panicked = CAPTURED
+            .with(|captured| captured.borrow_mut().take())
+            .unwrap_or(CapturedPanic {
+                message: String::new(),
+                location: None,
+                backtrace: None,
+            });
+        panicked.message = panic_message(payload.as_ref());
+        panicked
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn panics_become_problem_json_with_the_request_id() {
+        let layer = PanicCaptureLayer::default();
+        let service = layer.layer(service_fn(|_: Request<()>| async {
+            let denominator = 0;
+            if denominator == 0 {
+                panic!("numerator cannot be zero");
+            }
+            Ok::<_, std::convert::Infallible>(().into_response())
+        }));
+
+        let req = Request::builder()
+            .uri("/numbers/divide")
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .body(())
+            .unwrap();
+        let res = service.oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!("application/problem+json", res.headers()[CONTENT_TYPE]);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#""correlation_id":"abc-123""#), "{body}");
+        assert!(body.contains(r#""instance":"/numbers/divide""#), "{body}");
+        assert!(!body.contains("backtrace"), "{body}");
+        assert_eq!(1, layer.panic_count());
+    }
+
+    #[test]
+    fn captures_the_message_and_location() {
+        let panicked = capture(true, || panic!("oh no {}", 42)).unwrap_err();
+
+        assert_eq!("oh no 42", panicked.message);
+        assert!(panicked
+            .location
+            .unwrap()
+            .contains("panic_capture_layer.rs"));
+        assert!(panicked.backtrace.is_some());
+    }
+}
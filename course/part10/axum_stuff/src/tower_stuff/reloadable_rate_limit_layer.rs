@@ -0,0 +1,160 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::time::{Instant, Sleep};
+use tower::{Layer, Service};
+use tracing::{info, trace};
+
+// the rate used by ReloadableRateLimitLayer, clones share the same rate so it can be
+// changed whilst the server is running
+#[derive(Debug, Clone)]
+pub struct ReloadableRate {
+    rate: Arc<RwLock<(u64, Duration)>>,
+}
+
+impl ReloadableRate {
+    // allows `num` calls every `per`
+    pub fn new(num: u64, per: Duration) -> Self {
+        Self {
+            rate: Arc::new(RwLock::new((num, per))),
+        }
+    }
+
+    // takes effect from the next period
+    pub fn set(&self, num: u64, per: Duration) {
+        info!(num, ?per, "rate limit changed");
+        *self.rate.write().unwrap() = (num, per);
+    }
+
+    pub fn get(&self) -> (u64, Duration) {
+        *self.rate.read().unwrap()
+    }
+}
+
+// the same as tower's RateLimitLayer, except the rate can be changed at runtime
+#[derive(Debug, Clone)]
+pub struct ReloadableRateLimitLayer {
+    rate: ReloadableRate,
+}
+
+impl ReloadableRateLimitLayer {
+    pub fn new(rate: ReloadableRate) -> Self {
+        Self { rate }
+    }
+}
+
+impl<S> Layer<S> for ReloadableRateLimitLayer {
+    type Service = ReloadableRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        let until = Instant::now();
+        ReloadableRateLimit {
+            inner,
+            rate: self.rate.clone(),
+            state: State::Ready {
+                until,
+                rem: self.rate.get().0,
+            },
+            sleep: Box::pin(tokio::time::sleep_until(until)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Limited,
+    Ready { until: Instant, rem: u64 },
+}
+
+#[derive(Debug)]
+pub struct ReloadableRateLimit<S> {
+    inner: S,
+    rate: ReloadableRate,
+    state: State,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<S, Req> Service<Req> for ReloadableRateLimit<S>
+where
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let State::Limited = self.state {
+            if self.sleep.as_mut().poll(cx).is_pending() {
+                trace!("rate limit exceeded, sleeping");
+                return Poll::Pending;
+            }
+
+            let (num, per) = self.rate.get();
+            self.state = State::Ready {
+                until: Instant::now() + per,
+                rem: num,
+            };
+        }
+
+        Poll::Ready(ready!(self.inner.poll_ready(cx)))
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let State::Ready { mut until, mut rem } = self.state else {
+            panic!("service not ready; poll_ready must be called first");
+        };
+
+        // if the period has elapsed, start a new one with the latest rate
+        let now = Instant::now();
+        if now >= until {
+            let (num, per) = self.rate.get();
+            until = now + per;
+            rem = num;
+        }
+
+        if rem > 1 {
+            self.state = State::Ready {
+                until,
+                rem: rem - 1,
+            };
+        } else {
+            self.sleep.as_mut().reset(until);
+            self.state = State::Limited;
+        }
+
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::ready;
+
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn new_rates_apply_from_the_next_period() {
+        let period = Duration::from_millis(100);
+        let rate = ReloadableRate::new(1, period);
+        let mut service = ReloadableRateLimitLayer::new(rate.clone())
+            .layer(service_fn(|()| ready(Ok::<_, ()>(()))));
+
+        service.ready().await.unwrap().call(()).await.unwrap();
+        rate.set(3, period);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            service.ready().await.unwrap().call(()).await.unwrap();
+        }
+        // the first period only allowed one call, the next allows all three
+        let elapsed = start.elapsed();
+        assert!(elapsed >= period / 2 && elapsed < period * 2, "{elapsed:?}");
+    }
+}
@@ -0,0 +1,15 @@
+use std::net::SocketAddr;
+
+use hyper::server::conn::AddrStream;
+
+// a connection that knows who's on the other end, which is all the make service
+// layers need from it. lets them be used with any transport, not just plain tcp
+pub trait RemoteAddr {
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl RemoteAddr for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use http::{HeaderValue, Request, Response};
+use pin_project::pin_project;
+use tower::{Layer, Service};
+use tracing::{field, info_span, Span};
+use uuid::Uuid;
+
+// the header request ids are read from & written to
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// anything longer than this is ignored & replaced, so clients can't bloat our logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// the id of a single request, available to handlers as a request extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn new() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    // an id sent by the client, if it's one we're happy to log & pass on
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let id = value.to_str().ok()?;
+        (!id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN).then(|| Self(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// accepts the client's x-request-id or generates one, then runs the request in a
+// `request` span with the id recorded on it & echoes the id back in the response.
+// the id is also written to the request's headers so inner layers see the same one
+#[derive(Debug, Clone, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for RequestIdService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = RequestIdFut<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::new);
+        // only ascii ids make it this far, so this can't fail
+        let header = HeaderValue::from_str(request_id.as_str()).unwrap();
+
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            uri = %req.uri(),
+            // set by ResponseCacheLayer on the routes it caches
+            cache = field::Empty,
+        );
+        req.headers_mut().insert(REQUEST_ID_HEADER, header.clone());
+        req.extensions_mut().insert(request_id);
+
+        let fut = {
+            let _entered = span.enter();
+            self.inner.call(req)
+        };
+        RequestIdFut { fut, span, header }
+    }
+}
+
+#[pin_project]
+pub struct RequestIdFut<F> {
+    #[pin]
+    fut: F,
+    span: Span,
+    header: HeaderValue,
+}
+
+impl<F, ResBody, E> Future for RequestIdFut<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _entered = this.span.enter();
+        let mut res = ready!(this.fut.poll(cx))?;
+        res.headers_mut()
+            .insert(REQUEST_ID_HEADER, this.header.clone());
+        Poll::Ready(Ok(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    async fn echo_request_id(req: Request<()>) -> Result<Response<String>, Infallible> {
+        let extension = req.extensions().get::<RequestId>().unwrap().to_string();
+        assert_eq!(extension, req.headers()[REQUEST_ID_HEADER]);
+        Ok(Response::new(extension))
+    }
+
+    #[tokio::test]
+    async fn generated_ids_are_shared_with_the_handler_and_response() {
+        let service = RequestIdLayer.layer(service_fn(echo_request_id));
+
+        let res = service.oneshot(Request::new(())).await.unwrap();
+
+        let id = res.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(Uuid::parse_str(id).is_ok(), "{id}");
+        assert_eq!(id, res.body());
+    }
+
+    #[tokio::test]
+    async fn client_ids_are_kept_unless_unusable() {
+        let service = RequestIdLayer.layer(service_fn(echo_request_id));
+        let request = |id: &str| {
+            Request::builder()
+                .header(REQUEST_ID_HEADER, id)
+                .body(())
+                .unwrap()
+        };
+
+        let res = service.clone().oneshot(request("abc-123")).await.unwrap();
+        assert_eq!("abc-123", res.headers()[REQUEST_ID_HEADER]);
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        let res = service.oneshot(request(&too_long)).await.unwrap();
+        assert_ne!(too_long, res.headers()[REQUEST_ID_HEADER]);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::{sync::Semaphore, task::AbortHandle};
+use tracing::info;
+
+// a semaphore whose total number of permits can be changed whilst permits are held,
+// for limits that can be reloaded at runtime. clones share the same semaphore
+#[derive(Debug, Clone)]
+pub struct ResizableSemaphore {
+    sema: Arc<Semaphore>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    size: usize,
+    // permits that have to be forgotten as they're released to finish shrinking
+    debt: usize,
+    // the task waiting to forget the debt, replaced on every resize
+    shrink: Option<AbortHandle>,
+    generation: u64,
+}
+
+impl ResizableSemaphore {
+    pub fn new(size: usize) -> Self {
+        Self {
+            sema: Arc::new(Semaphore::new(size)),
+            state: Arc::new(Mutex::new(State {
+                size,
+                debt: 0,
+                shrink: None,
+                generation: 0,
+            })),
+        }
+    }
+
+    pub fn semaphore(&self) -> Arc<Semaphore> {
+        self.sema.clone()
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    // growing takes effect straight away, paying off any shrink that's still waiting
+    // first. when shrinking, permits that are held aren't taken back, they're
+    // forgotten as they're released instead
+    pub fn resize(&self, new_size: usize) {
+        let mut state = self.state.lock().unwrap();
+        // a shrink that's still waiting is worked out again from the debt
+        if let Some(shrink) = state.shrink.take() {
+            shrink.abort();
+        }
+        state.generation += 1;
+
+        if new_size >= state.size {
+            let grow_by = new_size - state.size;
+            let paid_off = grow_by.min(state.debt);
+            state.debt -= paid_off;
+            self.sema.add_permits(grow_by - paid_off);
+        } else {
+            state.debt += state.size - new_size;
+            state.debt -= self.sema.forget_permits(state.debt);
+        }
+        state.size = new_size;
+
+        if state.debt > 0 {
+            info!(
+                outstanding = state.debt,
+                "waiting for permits to be released to shrink"
+            );
+            let debt = state.debt;
+            let generation = state.generation;
+            let sema = self.sema.clone();
+            let shared = self.state.clone();
+            let shrink = tokio::spawn(async move {
+                let outstanding = u32::try_from(debt).unwrap_or(u32::MAX);
+                let Ok(permits) = sema.acquire_many_owned(outstanding).await else {
+                    return;
+                };
+                let mut state = shared.lock().unwrap();
+                // resized whilst acquiring, so the permits are given back to be
+                // worked out again
+                if state.generation == generation {
+                    permits.forget();
+                    state.debt -= outstanding as usize;
+                    state.shrink = None;
+                }
+            });
+            state.shrink = Some(shrink.abort_handle());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shrinking_waits_for_held_permits() {
+        let sema = ResizableSemaphore::new(3);
+        let held = sema.semaphore().acquire_many_owned(2).await.unwrap();
+
+        sema.resize(1);
+        assert_eq!(0, sema.semaphore().available_permits());
+
+        drop(held);
+        tokio::task::yield_now().await;
+        assert_eq!(1, sema.semaphore().available_permits());
+
+        sema.resize(4);
+        assert_eq!(4, sema.semaphore().available_permits());
+        assert_eq!(4, sema.size());
+    }
+
+    #[tokio::test]
+    async fn growing_pays_off_a_shrink_that_is_still_waiting() {
+        let sema = ResizableSemaphore::new(3);
+        let held = sema.semaphore().acquire_many_owned(3).await.unwrap();
+
+        sema.resize(1);
+        sema.resize(2);
+        assert_eq!(0, sema.semaphore().available_permits());
+
+        drop(held);
+        tokio::task::yield_now().await;
+        // only the one permit still owed from the shrink is forgotten
+        assert_eq!(2, sema.semaphore().available_permits());
+        assert_eq!(2, sema.size());
+
+        sema.resize(3);
+        assert_eq!(3, sema.semaphore().available_permits());
+    }
+}
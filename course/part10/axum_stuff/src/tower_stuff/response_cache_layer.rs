@@ -0,0 +1,427 @@
+use std::{
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{self, BoxBody, Bytes},
+    extract::OriginalUri,
+    response::IntoResponse,
+};
+use futures::future::BoxFuture;
+use http::{
+    header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+    HeaderMap,
+    HeaderName,
+    HeaderValue,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use hyper::body::HttpBody;
+use lru::LruCache;
+use tower::{BoxError, Layer, Service};
+use tracing::{debug, Span};
+
+use crate::api_error::{ApiError, ErrorCode};
+
+// the longest anything is cached for, whatever its max-age says, so a huge one can't
+// overflow working out when it expires
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// caches successful GET responses in memory for `ttl`, or the response's own max-age if
+// it has one, evicting the least recently used once they add up to more than
+// `max_bytes`. every response gets an ETag so clients can revalidate with
+// If-None-Match & get a 304 back. bodies are buffered, so it's only for routes whose
+// responses comfortably fit in memory. whether the cache was hit is recorded as
+// `cache` on the current span
+#[derive(Debug, Clone)]
+pub struct ResponseCacheLayer {
+    cache: Arc<Mutex<Cache>>,
+    ttl: Duration,
+    vary_by: Arc<Vec<HeaderName>>,
+}
+
+impl ResponseCacheLayer {
+    pub fn new(ttl: Duration, max_bytes: usize) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(Cache {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+                max_bytes,
+            })),
+            ttl,
+            vary_by: Arc::default(),
+        }
+    }
+
+    // responses are cached by path & query, plus the values of these request headers,
+    // e.g. accept-encoding when the inner service compresses. the path is the one the
+    // client asked for, not what's left of it after a nest stripped its prefix
+    pub fn vary_by(mut self, header: HeaderName) -> Self {
+        Arc::make_mut(&mut self.vary_by).push(header);
+        self
+    }
+
+    fn key<B>(&self, req: &Request<B>) -> CacheKey {
+        let headers = self
+            .vary_by
+            .iter()
+            .map(|name| req.headers().get(name).cloned())
+            .collect();
+        let uri = req
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(req.uri(), |OriginalUri(uri)| uri);
+        CacheKey {
+            path_and_query: uri.path_and_query().map_or("/", |pq| pq.as_str()).into(),
+            headers,
+        }
+    }
+}
+
+impl<S> Layer<S> for ResponseCacheLayer {
+    type Service = ResponseCache<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseCache {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path_and_query: String,
+    headers: Vec<Option<HeaderValue>>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    expires: Instant,
+}
+
+impl CachedResponse {
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>()
+    }
+
+    fn response(&self, if_none_match: Option<&HeaderValue>) -> Response<BoxBody> {
+        let max_age = self.expires.saturating_duration_since(Instant::now());
+        let max_age = max_age.as_secs() + u64::from(max_age.subsec_nanos() > 0);
+        let mut headers = self.headers.clone();
+        headers
+            .entry(CACHE_CONTROL)
+            .or_insert_with(|| format!("max-age={max_age}").parse().unwrap());
+
+        let not_modified = match (if_none_match, headers.get(ETAG)) {
+            (Some(if_none_match), Some(etag)) => etag_matches(if_none_match, etag),
+            _ => false,
+        };
+        if not_modified {
+            let mut res = Response::new(body::boxed(http_body::Empty::new()));
+            *res.status_mut() = StatusCode::NOT_MODIFIED;
+            // only the headers that would've been sent with a 200
+            for name in [CACHE_CONTROL, ETAG] {
+                if let Some(value) = headers.remove(&name) {
+                    res.headers_mut().insert(name, value);
+                }
+            }
+            return res;
+        }
+
+        let mut res = Response::new(body::boxed(http_body::Full::new(self.body.clone())));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = headers;
+        res
+    }
+}
+
+// true if any of the etags in an If-None-Match header match, ignoring whether
+// they're weak
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let etag = etag.to_str().map(strip_weak).unwrap_or_default();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+}
+
+fn etag(body: &[u8]) -> HeaderValue {
+    format!("W/\"{:x}-{:08x}\"", body.len(), crc32fast::hash(body))
+        .parse()
+        .unwrap()
+}
+
+// how long the response says it can be cached for, up to MAX_TTL, or None if it mustn't be
+fn cacheable_for(headers: &HeaderMap, default: Duration) -> Option<Duration> {
+    let Some(cache_control) = headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) else {
+        return Some(default.min(MAX_TTL));
+    };
+    let mut ttl = default;
+    for directive in cache_control.split(',').map(str::trim) {
+        if matches!(directive, "no-store" | "no-cache" | "private") {
+            return None;
+        }
+        if let Some(secs) = directive.strip_prefix("max-age=") {
+            ttl = Duration::from_secs(secs.parse().ok()?);
+        }
+    }
+    Some(ttl.min(MAX_TTL))
+}
+
+#[derive(Debug)]
+struct Cache {
+    entries: LruCache<CacheKey, CachedResponse>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Cache {
+    fn get(&mut self, key: &CacheKey) -> Option<CachedResponse> {
+        let cached = self.entries.get(key)?;
+        if cached.expires > Instant::now() {
+            return Some(cached.clone());
+        }
+        self.remove(key);
+        None
+    }
+
+    fn insert(&mut self, key: CacheKey, cached: CachedResponse) {
+        let size = cached.size();
+        if size > self.max_bytes {
+            return;
+        }
+        self.remove(&key);
+        while self.bytes + size > self.max_bytes {
+            let Some((_, evicted)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.bytes -= evicted.size();
+        }
+        self.bytes += size;
+        self.entries.put(key, cached);
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(removed) = self.entries.pop(key) {
+            self.bytes -= removed.size();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseCache<S> {
+    inner: S,
+    layer: ResponseCacheLayer,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for ResponseCache<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // the ready service is the one that has to be called
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        if req.method() != Method::GET {
+            Span::current().record("cache", "bypass");
+            return Box::pin(async move {
+                let res = inner.call(req).await?;
+                Ok(res.map(body::boxed))
+            });
+        }
+
+        let layer = self.layer.clone();
+        let key = layer.key(&req);
+        let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+        // lets clients skip the cache, the fresh response still replaces what's cached
+        let no_cache = req
+            .headers()
+            .get(CACHE_CONTROL)
+            .is_some_and(|v| v.as_bytes() == b"no-cache");
+
+        if !no_cache {
+            if let Some(cached) = layer.cache.lock().unwrap().get(&key) {
+                Span::current().record("cache", "hit");
+                return Box::pin(async move { Ok(cached.response(if_none_match.as_ref())) });
+            }
+        }
+        Span::current().record("cache", "miss");
+
+        Box::pin(async move {
+            let res = inner.call(req).await?;
+            let ttl = cacheable_for(res.headers(), layer.ttl);
+            let (Some(ttl), StatusCode::OK) = (ttl, res.status()) else {
+                return Ok(res.map(body::boxed));
+            };
+
+            let (parts, res_body) = res.into_parts();
+            let body = match hyper::body::to_bytes(res_body).await {
+                Ok(body) => body,
+                Err(err) => {
+                    let err: BoxError = err.into();
+                    return Ok(ApiError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        ErrorCode::Internal,
+                        format!("failed to read response: {err}"),
+                    )
+                    .into_response());
+                }
+            };
+            let mut headers = parts.headers;
+            headers.entry(ETAG).or_insert_with(|| etag(&body));
+
+            let cached = CachedResponse {
+                status: parts.status,
+                headers,
+                body,
+                expires: Instant::now() + ttl,
+            };
+            debug!(key = ?key.path_and_query, ?ttl, "caching response");
+            let res = cached.response(if_none_match.as_ref());
+            layer.cache.lock().unwrap().insert(key, cached);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use http::header::ACCEPT_ENCODING;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn counting_service(
+        layer: ResponseCacheLayer,
+        calls: Arc<AtomicUsize>,
+    ) -> impl Service<Request<()>, Response = Response<BoxBody>, Error = Infallible> + Clone {
+        layer.layer(service_fn(move |req: Request<()>| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let body = format!("{} {call}", req.uri().path());
+                Ok::<_, Infallible>(Response::new(http_body::Full::new(Bytes::from(body))))
+            }
+        }))
+    }
+
+    async fn body_string(res: Response<BoxBody>) -> String {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn huge_max_ages_are_capped() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("max-age=18446744073709551615"),
+        );
+        assert_eq!(
+            Some(MAX_TTL),
+            cacheable_for(&headers, Duration::from_secs(60))
+        );
+        assert_eq!(
+            Some(MAX_TTL),
+            cacheable_for(&HeaderMap::new(), Duration::MAX)
+        );
+
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=5"));
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            cacheable_for(&headers, Duration::from_secs(60))
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_are_cached_per_key_and_revalidated_with_etags() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let layer = ResponseCacheLayer::new(Duration::from_secs(60), 1024).vary_by(ACCEPT_ENCODING);
+        let service = counting_service(layer, calls.clone());
+        let get = |path: &str, encoding: &str| {
+            Request::get(path)
+                .header(ACCEPT_ENCODING, encoding)
+                .body(())
+                .unwrap()
+        };
+
+        let first = service.clone().oneshot(get("/a", "gzip")).await.unwrap();
+        let etag = first.headers()[ETAG].clone();
+        assert_eq!("max-age=60", first.headers()[CACHE_CONTROL]);
+        assert_eq!("/a 0", body_string(first).await);
+
+        let hit = service.clone().oneshot(get("/a", "gzip")).await.unwrap();
+        assert_eq!(etag, hit.headers()[ETAG]);
+        assert_eq!("/a 0", body_string(hit).await);
+
+        // a different path or varied header is cached separately
+        let res = service.clone().oneshot(get("/a", "br")).await.unwrap();
+        assert_eq!("/a 1", body_string(res).await);
+        let res = service.clone().oneshot(get("/b", "gzip")).await.unwrap();
+        assert_eq!("/b 2", body_string(res).await);
+
+        let mut revalidate = get("/a", "gzip");
+        revalidate.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+        let res = service.clone().oneshot(revalidate).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+        assert_eq!(etag, res.headers()[ETAG]);
+        assert_eq!("", body_string(res).await);
+
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_responses_are_evicted_when_full() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        // room for two responses & their etags
+        let layer = ResponseCacheLayer::new(Duration::from_secs(60), 60);
+        let service = counting_service(layer, calls.clone());
+        let get = |path: &str| {
+            let service = service.clone();
+            let req = Request::get(path).body(()).unwrap();
+            async move { body_string(service.oneshot(req).await.unwrap()).await }
+        };
+
+        assert_eq!("/a 0", get("/a").await);
+        assert_eq!("/b 1", get("/b").await);
+        // /b is now the least recently used
+        assert_eq!("/a 0", get("/a").await);
+        assert_eq!("/c 2", get("/c").await);
+
+        assert_eq!("/a 0", get("/a").await);
+        assert_eq!("/b 3", get("/b").await);
+    }
+}
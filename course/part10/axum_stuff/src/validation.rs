@@ -0,0 +1,137 @@
+use std::{fmt::Display, ops::RangeInclusive};
+
+use axum::{async_trait, extract::FromRequest, http::Request};
+use http::StatusCode;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::api_error::{ApiError, ErrorCode, Json};
+
+// the constraints on a request body, checked by the Valid extractor once it's been
+// deserialized, e.g.
+//
+//     fn validate(&self, v: &mut Validator) {
+//         v.not_zero("denominator", self.denominator)
+//             .range("numerator", self.numerator, -100..=100);
+//     }
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// collects every field that's invalid, rather than stopping at the first
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, field: &str, valid: bool, message: impl Into<String>) -> &mut Self {
+        if !valid {
+            self.errors.push(FieldError {
+                field: field.to_string(),
+                message: message.into(),
+            });
+        }
+        self
+    }
+
+    pub fn not_zero<T: PartialEq + Default>(&mut self, field: &str, value: T) -> &mut Self {
+        self.check(field, value != T::default(), "must not be zero")
+    }
+
+    pub fn range<T: PartialOrd + Display>(
+        &mut self,
+        field: &str,
+        value: T,
+        range: RangeInclusive<T>,
+    ) -> &mut Self {
+        let message = format!("must be between {} and {}", range.start(), range.end());
+        self.check(field, range.contains(&value), message)
+    }
+}
+
+pub fn validate<T: Validate>(value: &T) -> Result<(), ApiError> {
+    let mut validator = Validator::default();
+    value.validate(&mut validator);
+    if validator.errors.is_empty() {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::ValidationFailed,
+        "the request body is invalid",
+    )
+    .with_details(validator.errors))
+}
+
+// a Json body which has also been validated
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Valid<T>
+where
+    T: Validate,
+    Json<T>: FromRequest<S, B, Rejection = ApiError>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        validate(&value)?;
+        Ok(Valid(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Numbers {
+        numerator: isize,
+        denominator: isize,
+    }
+
+    impl Validate for Numbers {
+        fn validate(&self, v: &mut Validator) {
+            v.not_zero("denominator", self.denominator)
+                .range("numerator", self.numerator, -100..=100)
+                .range("denominator", self.denominator, -100..=100);
+        }
+    }
+
+    #[test]
+    fn every_invalid_field_is_reported() {
+        assert!(validate(&Numbers {
+            numerator: 5,
+            denominator: 2
+        })
+        .is_ok());
+
+        let err = validate(&Numbers {
+            numerator: 500,
+            denominator: 0,
+        })
+        .unwrap_err();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, err.status());
+        assert_eq!(
+            serde_json::json!({
+                "code": "validation_failed",
+                "message": "the request body is invalid",
+                "details": [
+                    {"field": "denominator", "message": "must not be zero"},
+                    {"field": "numerator", "message": "must be between -100 and 100"},
+                ],
+            }),
+            serde_json::to_value(err.into_body()).unwrap()
+        );
+    }
+}